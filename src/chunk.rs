@@ -1,14 +1,29 @@
 use num_derive::FromPrimitive;
 use num_derive::ToPrimitive;
 use num_traits::ToPrimitive;
+use std::rc::Rc;
 use std::vec::Vec;
 
-#[derive(FromPrimitive, ToPrimitive)]
+use crate::scanner::*;
+use crate::value::*;
+
+#[derive(FromPrimitive, ToPrimitive, Copy, Clone, PartialEq, Debug)]
 pub enum Op {
     Constant,
     Nil,
     False,
     True,
+    Pop,
+    GetLocal,
+    SetLocal,
+    GetGlobal,
+    DefineGlobal,
+    SetGlobal,
+    GetUpvalue,
+    SetUpvalue,
+    GetProperty,
+    SetProperty,
+    GetSuper,
     Equal,
     Greater,
     Less,
@@ -18,49 +33,448 @@ pub enum Op {
     Divide,
     Not,
     Negate,
+    Print,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    Call,
+    Invoke,
+    SuperInvoke,
+    Closure,
+    CloseUpvalue,
     Return,
-}
+    Class,
+    Inherit,
+    Method,
+    Modulo,
+    Dup,  // Pushes a copy of the top of the stack.
+    Over, // Pushes a copy of the value under the top.
+    Swap,
 
-pub struct Chunk {
-    pub code: Vec<u8>,
-    pub lines: Vec<u16>,
-    pub constants: Vec<Constant>,
+    // Same as the instructions above but with a 24-bit constant index, for
+    // chunks with more than 256 constants.
+    ConstantLong,
+    GetGlobalLong,
+    DefineGlobalLong,
+    SetGlobalLong,
+    GetPropertyLong,
+    SetPropertyLong,
+    GetSuperLong,
+    InvokeLong,
+    SuperInvokeLong,
+    ClosureLong,
+    ClassLong,
+    MethodLong,
 }
 
-pub enum Constant {
-    Number(f64),
-    String(String),
-}
+pub const MAX_CONSTANTS: usize = 1 << 24;
 
-impl Constant {
-    pub fn print(&self) {
+impl Op {
+    /// The 24-bit operand form of a constant-indexed instruction.
+    pub fn long(self) -> Op {
         match self {
-            Constant::Number(n) => print!("{}", n),
-            Constant::String(s) => print!("{}", s),
+            Op::Constant     => Op::ConstantLong,
+            Op::GetGlobal    => Op::GetGlobalLong,
+            Op::DefineGlobal => Op::DefineGlobalLong,
+            Op::SetGlobal    => Op::SetGlobalLong,
+            Op::GetProperty  => Op::GetPropertyLong,
+            Op::SetProperty  => Op::SetPropertyLong,
+            Op::GetSuper     => Op::GetSuperLong,
+            Op::Invoke       => Op::InvokeLong,
+            Op::SuperInvoke  => Op::SuperInvokeLong,
+            Op::Closure      => Op::ClosureLong,
+            Op::Class        => Op::ClassLong,
+            Op::Method       => Op::MethodLong,
+            _ => panic!("{:?} has no long form.", self),
         }
     }
+
+    pub fn is_long(self) -> bool {
+        ToPrimitive::to_u8(&self).unwrap() >= ToPrimitive::to_u8(&Op::ConstantLong).unwrap()
+    }
+}
+
+pub struct Chunk {
+    pub code: Vec<u8>,
+    lines: RunTable<u32>,
+    extents: RunTable<Extent>,
+    pub constants: Vec<Value>,
+    pub source: Rc<str>, // the text the spans point into
+}
+
+// The part of the source a run of bytes was compiled from. Lines are kept
+// in their own table since they change far less often, and the column is
+// worked out from the source when it's needed.
+#[derive(Copy, Clone, PartialEq, Default)]
+struct Extent {
+    start: u32,
+    length: u32,
 }
 
 impl Chunk {
     pub fn new() -> Chunk {
         Chunk {
             code: Vec::new(),
-            lines: Vec::new(),
+            lines: RunTable::new(),
+            extents: RunTable::new(),
             constants: Vec::new(),
+            source: Rc::from(""),
         }
     }
 
-    pub fn write(&mut self, byte: u8, line: u16) {
+    pub fn write(&mut self, byte: u8, span: Span) {
+        let offset = self.code.len() as u32;
+        self.lines.push(offset, span.line);
+        self.extents.push(offset, Extent {
+            start: span.start,
+            length: span.length,
+        });
         self.code.push(byte);
-        self.lines.push(line);
     }
 
-    pub fn write_op(&mut self, op: Op, line: u16) {
-        self.write(ToPrimitive::to_u8(&op).unwrap(), line);
+    pub fn write_op(&mut self, op: Op, span: Span) {
+        self.write(ToPrimitive::to_u8(&op).unwrap(), span);
     }
 
-    pub fn add_constant(&mut self, constant: Constant) -> usize {
+    /// Drops the code from `offset` on, so the compiler can replace an
+    /// instruction it has just emitted.
+    pub fn truncate(&mut self, offset: usize) {
+        self.code.truncate(offset);
+        self.lines.truncate(offset);
+        self.extents.truncate(offset);
+    }
+
+    pub fn get_span(&self, offset: usize) -> Span {
+        let extent = self.extents.get(offset);
+        let start = (extent.start as usize).min(self.source.len());
+        let line_start = self.source[..start].rfind('\n').map_or(0, |newline| newline + 1);
+        let column = self.source[line_start..start].chars().count() + 1;
+
+        return Span {
+            start: extent.start,
+            length: extent.length,
+            line: self.get_line(offset),
+            column: column as u32,
+        };
+    }
+
+    pub fn get_line(&self, offset: usize) -> u32 {
+        return self.lines.get(offset);
+    }
+
+    pub fn add_constant(&mut self, constant: Value) -> usize {
         self.constants.push(constant);
         return self.constants.len() - 1;
     }
+
+    pub fn allocated_bytes(&self) -> usize {
+        return self.code.capacity()
+            + self.constants.capacity() * std::mem::size_of::<Value>()
+            + self.lines.allocated_bytes()
+            + self.extents.allocated_bytes();
+    }
+}
+
+// Values that stay the same across runs of consecutive bytes, stored as
+// the difference from the run before so that most runs fit in a couple of
+// bytes. Checkpoints let a lookup start decoding close to its offset.
+struct RunTable<T> {
+    bytes: Vec<u8>,
+    checkpoints: Vec<Checkpoint<T>>,
+    runs: usize,
+    last: Option<Run<T>>,
+}
+
+#[derive(Copy, Clone, Default)]
+struct Run<T> {
+    offset: u32,
+    value: T,
+}
+
+// Saved every CHECKPOINT_INTERVAL runs.
+struct Checkpoint<T> {
+    position: u32,     // in `bytes`, of the run at `offset`
+    offset: u32,
+    previous: Run<T>,  // what that run's deltas are relative to
+}
+
+const CHECKPOINT_INTERVAL: usize = 64;
+
+trait Delta: Copy + PartialEq + Default {
+    fn encode(run: Run<Self>, previous: Run<Self>, bytes: &mut Vec<u8>);
+    fn decode(previous: Run<Self>, bytes: &[u8], position: &mut usize) -> Run<Self>;
+}
+
+impl<T: Delta> RunTable<T> {
+    fn new() -> RunTable<T> {
+        RunTable {
+            bytes: Vec::new(),
+            checkpoints: Vec::new(),
+            runs: 0,
+            last: None,
+        }
+    }
+
+    fn push(&mut self, offset: u32, value: T) {
+        if self.last.is_some_and(|last| last.value == value) {
+            return;
+        }
+
+        let run = Run { offset, value };
+        let previous = self.last.unwrap_or_default();
+        if self.runs.is_multiple_of(CHECKPOINT_INTERVAL) {
+            self.checkpoints.push(Checkpoint {
+                position: self.bytes.len() as u32,
+                offset,
+                previous,
+            });
+        }
+
+        T::encode(run, previous, &mut self.bytes);
+        self.runs += 1;
+        self.last = Some(run);
+    }
+
+    fn get(&self, offset: usize) -> T {
+        let checkpoint = &self.checkpoints[self.checkpoints.partition_point(|c| c.offset as usize <= offset) - 1];
+        let mut position = checkpoint.position as usize;
+        let mut run = checkpoint.previous;
+        while position < self.bytes.len() {
+            let mut next = position;
+            let decoded = T::decode(run, &self.bytes, &mut next);
+            if decoded.offset as usize > offset {
+                break;
+            }
+            run = decoded;
+            position = next;
+        }
+        return run.value;
+    }
+
+    fn allocated_bytes(&self) -> usize {
+        return self.bytes.capacity() + self.checkpoints.capacity() * std::mem::size_of::<Checkpoint<T>>();
+    }
+
+    fn truncate(&mut self, offset: usize) {
+        if self.last.is_none_or(|last| (last.offset as usize) < offset) {
+            return;
+        }
+
+        let checkpoint = self.checkpoints.partition_point(|c| (c.offset as usize) < offset);
+        if checkpoint == 0 {
+            *self = RunTable::new();
+            return;
+        }
+
+        // Decode from the checkpoint up to the first run being dropped.
+        let checkpoint = checkpoint - 1;
+        let mut position = self.checkpoints[checkpoint].position as usize;
+        let mut run = self.checkpoints[checkpoint].previous;
+        let mut runs = checkpoint * CHECKPOINT_INTERVAL;
+        loop {
+            let mut next = position;
+            let decoded = T::decode(run, &self.bytes, &mut next);
+            if decoded.offset as usize >= offset {
+                break;
+            }
+            run = decoded;
+            position = next;
+            runs += 1;
+        }
+
+        self.bytes.truncate(position);
+        self.checkpoints.truncate(checkpoint + 1);
+        self.runs = runs;
+        self.last = Some(run);
+    }
+}
+
+impl Delta for u32 {
+    fn encode(run: Run<u32>, previous: Run<u32>, bytes: &mut Vec<u8>) {
+        write_varint(bytes, (run.offset - previous.offset) as u64);
+        write_varint(bytes, zigzag(run.value as i64 - previous.value as i64));
+    }
+
+    fn decode(previous: Run<u32>, bytes: &[u8], position: &mut usize) -> Run<u32> {
+        let offset = previous.offset + read_varint(bytes, position) as u32;
+        let value = previous.value as i64 + unzigzag(read_varint(bytes, position));
+        return Run {
+            offset,
+            value: value as u32,
+        };
+    }
+}
+
+impl Delta for Extent {
+    // Runs are rarely more than three bytes long, so that goes in the low
+    // bits of the length, with zero meaning it follows on its own.
+    fn encode(run: Run<Extent>, previous: Run<Extent>, bytes: &mut Vec<u8>) {
+        let distance = run.offset - previous.offset;
+        let packed = if distance <= 3 { distance } else { 0 };
+        write_varint(bytes, (run.value.length as u64) << 2 | packed as u64);
+        if packed == 0 {
+            write_varint(bytes, distance as u64);
+        }
+        write_varint(bytes, zigzag(run.value.start as i64 - previous.value.start as i64));
+    }
+
+    fn decode(previous: Run<Extent>, bytes: &[u8], position: &mut usize) -> Run<Extent> {
+        let head = read_varint(bytes, position);
+        let distance = match head & 3 {
+            0 => read_varint(bytes, position),
+            packed => packed,
+        };
+        let start = previous.value.start as i64 + unzigzag(read_varint(bytes, position));
+        return Run {
+            offset: previous.offset + distance as u32,
+            value: Extent {
+                start: start as u32,
+                length: (head >> 2) as u32,
+            },
+        };
+    }
+}
+
+// Seven bits per byte, low bits first, with the top bit set on every byte
+// but the last.
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], position: &mut usize) -> u64 {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*position];
+        *position += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+// Interleaves negative and positive differences so small ones of either
+// sign stay small.
+fn zigzag(value: i64) -> u64 {
+    return ((value << 1) ^ (value >> 63)) as u64;
+}
+
+fn unzigzag(value: u64) -> i64 {
+    return ((value >> 1) as i64) ^ -((value & 1) as i64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(start: u32, line: u32) -> Span {
+        Span {
+            start,
+            length: 1,
+            line,
+            column: 1,
+        }
+    }
+
+    #[test]
+    fn get_line_matches_the_line_each_byte_was_written_with() {
+        // (span start, line, bytes emitted for that token), including a
+        // token that spans several bytes and a jump back to an earlier line.
+        let writes = [(0, 1, 2), (4, 1, 1), (10, 2, 3), (20, 4, 1), (4, 1, 2), (30, 70000, 1)];
+
+        let mut chunk = Chunk::new();
+        let mut expected = Vec::new();
+        for &(start, line, count) in writes.iter() {
+            for _ in 0..count {
+                chunk.write(0, span(start, line));
+                expected.push(line);
+            }
+        }
+
+        for (offset, &line) in expected.iter().enumerate() {
+            assert_eq!(chunk.get_line(offset), line, "line at offset {}", offset);
+        }
+        assert_eq!(chunk.lines.runs, 5);
+        assert_eq!(chunk.extents.runs, writes.len());
+    }
+
+    // Roughly what the compiler emits: five tokens to a line, each compiled
+    // to one to three bytes, with the last going back to the first.
+    fn program() -> (Chunk, Vec<Span>) {
+        let mut chunk = Chunk::new();
+        let mut expected = Vec::new();
+        let mut source = String::new();
+        for line in 1..=1000 {
+            let line_start = source.len() as u32;
+            source.push_str("ab cd éf gh ij\n");
+            for token in 0..5u32 {
+                let column = if token == 4 { 0 } else { token * 3 };
+                let span = Span {
+                    start: line_start + column + if column > 6 { 1 } else { 0 },
+                    length: 2,
+                    line,
+                    column: column + 1,
+                };
+                for _ in 0..token % 3 + 1 {
+                    chunk.write(0, span);
+                    expected.push(span);
+                }
+            }
+        }
+        chunk.source = Rc::from(source);
+        return (chunk, expected);
+    }
+
+    #[test]
+    fn get_span_matches_the_span_each_byte_was_written_with() {
+        let (chunk, expected) = program();
+        for (offset, &span) in expected.iter().enumerate() {
+            assert_eq!(chunk.get_span(offset), span, "span at offset {}", offset);
+        }
+    }
+
+    #[test]
+    fn span_table_is_smaller_than_one_line_per_byte() {
+        fn size<T>(table: &RunTable<T>) -> usize {
+            return table.bytes.len() + table.checkpoints.len() * std::mem::size_of::<Checkpoint<T>>();
+        }
+
+        // The table this replaced held a u16 line for every byte.
+        let (chunk, _) = program();
+        let table = size(&chunk.lines) + size(&chunk.extents);
+        let per_byte = chunk.code.len() * std::mem::size_of::<u16>();
+        assert!(table < per_byte, "{} bytes of spans against {}", table, per_byte);
+    }
+
+    #[test]
+    fn truncate_drops_the_spans_of_removed_code() {
+        let (mut chunk, expected) = program();
+        let span = Span {
+            start: 5,
+            length: 1,
+            line: 7,
+            column: 6,
+        };
+
+        for &offset in [9000, 8001, 4000, 130, 1].iter() {
+            chunk.truncate(offset);
+            assert_eq!(chunk.get_span(offset - 1), expected[offset - 1]);
+
+            // Writing again carries on from the right place.
+            chunk.write(0, span);
+            assert_eq!(chunk.get_span(offset), span);
+            assert_eq!(chunk.get_span(offset - 1), expected[offset - 1]);
+            chunk.truncate(offset);
+        }
+
+        chunk.truncate(0);
+        chunk.write(0, span);
+        assert_eq!(chunk.get_span(0), span);
+    }
 }
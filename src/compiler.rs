@@ -1,13 +1,95 @@
 use crate::chunk::*;
+use crate::diagnostic::*;
+use crate::memory::*;
+use crate::object::*;
 use crate::scanner::*;
+use crate::value::*;
+use crate::vm::*;
 
 use num_derive::FromPrimitive;
 use num_derive::ToPrimitive;
 use num_traits::ToPrimitive;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+const UINT8_COUNT: usize = u8::MAX as usize + 1;
 
 pub struct Compiler<'a> {
+    source: Rc<str>,
     parser: Parser<'a>,
-    compiling_chunk: &'a mut Chunk,
+    vm: &'a mut VM,
+    current: Box<FunctionCompiler>,
+    classes: Vec<ClassCompiler>,
+    last_target: Option<Target>,
+}
+
+struct FunctionCompiler {
+    enclosing: Option<Box<FunctionCompiler>>,
+    function: Function,
+    function_type: FunctionType,
+    locals: Vec<Local>,
+    upvalues: Vec<Capture>,
+    scope_depth: usize,
+    constants: HashMap<ConstantKey, usize>, // indexes of literals already in the chunk
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+struct ClassCompiler {
+    has_superclass: bool,
+}
+
+// A variable or property load, remembered so that a following `++` or `--`
+// can turn it into an update.
+#[derive(Copy, Clone)]
+struct Target {
+    kind: TargetKind,
+    name: Token,  // the variable or property, for the store's span
+    start: usize, // offset of the load instruction
+    end: usize,   // offset just past it
+}
+
+#[derive(Copy, Clone)]
+enum TargetKind {
+    Variable { set_op: Op, arg: usize },
+    Property { name: usize },
+}
+
+struct Local {
+    name: Token,
+    depth: Option<usize>, // None until the initializer has been compiled
+    is_captured: bool,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+struct Capture {
+    index: u8,
+    is_local: bool, // true if `index` is a local slot in the enclosing function
+}
+
+// Identifies literal constants that can share a slot in the constant pool.
+// Numbers compare by bit pattern so 0 and -0 stay distinct, and strings are
+// interned so comparing references compares their contents.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    String(Ref<LoxString>),
+}
+
+impl ConstantKey {
+    fn for_value(value: Value) -> Option<ConstantKey> {
+        match value {
+            Value::Number(n) => Some(ConstantKey::Number(n.to_bits())),
+            Value::Object(Obj::LString(s)) => Some(ConstantKey::String(s)),
+            _ => None,
+        }
+    }
 }
 
 struct Parser<'a> {
@@ -16,6 +98,7 @@ struct Parser<'a> {
     previous: Token,
     had_error: bool,
     panic_mode: bool,
+    diagnostics: Vec<Diagnostic>,
 }
 
 struct ParseRule<'a> {
@@ -24,7 +107,7 @@ struct ParseRule<'a> {
     precedence: Precedence,
 }
 
-type ParseFn<'a> = fn(&mut Compiler<'a>) -> ();
+type ParseFn<'a> = fn(&mut Compiler<'a>, bool) -> ();
 
 #[derive(FromPrimitive, ToPrimitive)]
 enum Precedence {
@@ -61,11 +144,15 @@ impl Precedence {
 
 fn get_rule<'a>(token_type: TokenType) -> ParseRule<'a> {
     match token_type {
-        TokenType::LeftParen    => ParseRule { prefix: Some(Compiler::grouping), infix: None,                   precedence: Precedence::None },
+        TokenType::LeftParen    => ParseRule { prefix: Some(Compiler::grouping), infix: Some(Compiler::call),   precedence: Precedence::Call },
+        TokenType::Dot          => ParseRule { prefix: None,                     infix: Some(Compiler::dot),    precedence: Precedence::Call },
         TokenType::Minus        => ParseRule { prefix: Some(Compiler::unary),    infix: Some(Compiler::binary), precedence: Precedence::Term },
         TokenType::Plus         => ParseRule { prefix: None,                     infix: Some(Compiler::binary), precedence: Precedence::Term },
         TokenType::Slash        |
-        TokenType::Star         => ParseRule { prefix: None,                     infix: Some(Compiler::binary), precedence: Precedence::Factor },
+        TokenType::Star         |
+        TokenType::Percent      => ParseRule { prefix: None,                     infix: Some(Compiler::binary), precedence: Precedence::Factor },
+        TokenType::PlusPlus     |
+        TokenType::MinusMinus   => ParseRule { prefix: Some(Compiler::prefix_increment), infix: Some(Compiler::postfix_increment), precedence: Precedence::Call },
         TokenType::Number       => ParseRule { prefix: Some(Compiler::number),   infix: None,                   precedence: Precedence::None },
         TokenType::Bang         => ParseRule { prefix: Some(Compiler::unary),    infix: None,                   precedence: Precedence::None },
         TokenType::BangEqual    |
@@ -78,31 +165,484 @@ fn get_rule<'a>(token_type: TokenType) -> ParseRule<'a> {
         TokenType::False        |
         TokenType::True         => ParseRule { prefix: Some(Compiler::literal),  infix: None,                   precedence: Precedence::None },
         TokenType::String       => ParseRule { prefix: Some(Compiler::string),   infix: None,                   precedence: Precedence::None },
+        TokenType::Identifier   => ParseRule { prefix: Some(Compiler::variable), infix: None,                   precedence: Precedence::None },
+        TokenType::Super        => ParseRule { prefix: Some(Compiler::super_),   infix: None,                   precedence: Precedence::None },
+        TokenType::This         => ParseRule { prefix: Some(Compiler::this),     infix: None,                   precedence: Precedence::None },
+        TokenType::And          => ParseRule { prefix: None,                     infix: Some(Compiler::and),    precedence: Precedence::And },
+        TokenType::Or           => ParseRule { prefix: None,                     infix: Some(Compiler::or),     precedence: Precedence::Or },
         _                       => ParseRule { prefix: None,                     infix: None,                   precedence: Precedence::None }
     }
 }
 
+// Folds into a double rather than an integer type so that long literals
+// lose precision instead of overflowing.
+fn parse_radix(digits: &str, radix: u32) -> f64 {
+    return digits
+        .chars()
+        .fold(0.0, |value, c| value * radix as f64 + c.to_digit(radix).unwrap() as f64);
+}
+
 impl<'a> Compiler<'a> {
-    pub fn new<'c>(source: &'c String, chunk: &'c mut Chunk) -> Compiler<'c> {
+    pub fn new<'c>(source: &'c str, vm: &'c mut VM) -> Compiler<'c> {
         let scanner = Scanner::new(source);
         let parser = Parser::new(scanner);
 
         Compiler {
+            source: Rc::from(source),
             parser: parser,
-            compiling_chunk: chunk,
+            vm: vm,
+            current: Box::new(FunctionCompiler::new(FunctionType::Script, None)),
+            classes: Vec::new(),
+            last_target: None,
         }
     }
 
-    pub fn compile(&mut self) -> bool {
+    pub fn compile(mut self) -> Result<Ref<Function>, Vec<Diagnostic>> {
         self.parser.advance();
+
+        while !self.parser.match_token(TokenType::EOF) {
+            self.declaration();
+        }
+
+        let (function, _) = self.end_compiler();
+
+        if self.parser.had_error {
+            return Err(self.parser.diagnostics);
+        }
+
+        return Ok(self.vm.heap.manage(function));
+    }
+
+    // Strings are the only objects the compiler allocates while functions are
+    // still under construction, so this is where it gives the collector a turn.
+    fn intern(&mut self, str: String) -> Ref<LoxString> {
+        if self.vm.heap.should_collect() {
+            self.mark_compiler_roots();
+            self.vm.collect_garbage();
+        }
+
+        self.vm.heap.manage_str(str)
+    }
+
+    fn mark_compiler_roots(&mut self) {
+        let mut compiler = Some(&*self.current);
+        while let Some(current) = compiler {
+            self.vm.heap.mark_function(&current.function);
+            compiler = current.enclosing.as_deref();
+        }
+    }
+
+    fn current_chunk(&mut self) -> &mut Chunk {
+        &mut self.current.function.chunk
+    }
+
+    fn declaration(&mut self) {
+        if self.parser.match_token(TokenType::Class) {
+            self.class_declaration();
+        } else if self.parser.match_token(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.parser.match_token(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.statement();
+        }
+
+        if self.parser.panic_mode {
+            self.parser.synchronize();
+        }
+    }
+
+    fn class_declaration(&mut self) {
+        self.parser.consume(TokenType::Identifier, "Expect class name.");
+        let name = self.parser.previous;
+        let name_constant = self.identifier_constant(&name);
+        self.declare_variable();
+
+        self.emit_constant_op(Op::Class, name_constant);
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassCompiler {
+            has_superclass: false,
+        });
+
+        if self.parser.match_token(TokenType::Less) {
+            self.parser.consume(TokenType::Identifier, "Expect superclass name.");
+            self.variable(false);
+
+            let superclass = self.parser.previous;
+            if self.parser.identifiers_equal(&name, &superclass) {
+                self.parser.error("A class can't inherit from itself.");
+            }
+
+            // Each subclass gets its own scope so `super` can be captured
+            // by its methods without clashing with sibling classes.
+            self.begin_scope();
+            self.add_local(Token::synthetic(TokenType::Super));
+            self.define_variable(0);
+
+            self.named_variable(&name, false);
+            self.emit_op(Op::Inherit);
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        // Keep the class on the stack while its methods are attached.
+        self.named_variable(&name, false);
+        self.parser.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.parser.check(TokenType::RightBrace) && !self.parser.check(TokenType::EOF) {
+            self.method();
+        }
+        self.parser.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_op(Op::Pop);
+
+        if self.classes.last().unwrap().has_superclass {
+            self.end_scope();
+        }
+
+        self.classes.pop();
+    }
+
+    fn method(&mut self) {
+        self.parser.consume(TokenType::Identifier, "Expect method name.");
+        let name = self.parser.previous;
+        let constant = self.identifier_constant(&name);
+
+        let function_type = if self.parser.scanner.get_lexeme(&name) == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type);
+
+        self.emit_constant_op(Op::Method, constant);
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    fn function(&mut self, function_type: FunctionType) {
+        let name = self.parser.scanner.get_lexeme(&self.parser.previous).to_string();
+        let name = self.intern(name);
+        let enclosing = std::mem::replace(
+            &mut self.current,
+            Box::new(FunctionCompiler::new(function_type, Some(name))),
+        );
+        self.current.enclosing = Some(enclosing);
+        self.begin_scope();
+
+        self.parser.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        if !self.parser.check(TokenType::RightParen) {
+            loop {
+                self.current.function.arity += 1;
+                if self.current.function.arity > 255 {
+                    self.parser.error_at_current("Can't have more than 255 parameters.");
+                }
+
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+
+                if !self.parser.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.parser.consume(TokenType::RightParen, "Expect ')' after parameters.");
+        self.parser.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        // No end_scope(): the callee's frame is discarded wholesale on return.
+        let (function, upvalues) = self.end_compiler();
+        let function = self.vm.heap.manage(function);
+        let constant = self.make_constant(Value::Object(Obj::Function(function)));
+        self.emit_constant_op(Op::Closure, constant);
+
+        for upvalue in upvalues {
+            self.emit_bytes(upvalue.is_local as u8, upvalue.index);
+        }
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+
+        if self.parser.match_token(TokenType::Equal) {
+            self.expression();
+        } else {
+            self.emit_op(Op::Nil);
+        }
+        self.parser.consume(TokenType::Semicolon, "Expect ';' after variable declaration.");
+
+        self.define_variable(global);
+    }
+
+    fn parse_variable(&mut self, error_message: &str) -> usize {
+        self.parser.consume(TokenType::Identifier, error_message);
+
+        self.declare_variable();
+        if self.current.scope_depth > 0 {
+            return 0;
+        }
+
+        let name = self.parser.previous;
+        return self.identifier_constant(&name);
+    }
+
+    fn identifier_constant(&mut self, name: &Token) -> usize {
+        let name = self.parser.scanner.get_lexeme(name).to_string();
+        let name = self.intern(name);
+        return self.make_constant(Value::Object(Obj::LString(name)));
+    }
+
+    fn declare_variable(&mut self) {
+        if self.current.scope_depth == 0 {
+            return;
+        }
+
+        let name = self.parser.previous;
+        let mut duplicate = false;
+        for local in self.current.locals.iter().rev() {
+            if let Some(depth) = local.depth {
+                if depth < self.current.scope_depth {
+                    break;
+                }
+            }
+
+            if self.parser.identifiers_equal(&name, &local.name) {
+                duplicate = true;
+                break;
+            }
+        }
+
+        if duplicate {
+            self.parser.error("Already a variable with this name in this scope.");
+        }
+
+        self.add_local(name);
+    }
+
+    fn add_local(&mut self, name: Token) {
+        if self.current.locals.len() == UINT8_COUNT {
+            self.parser.error("Too many local variables in function.");
+            return;
+        }
+
+        self.current.locals.push(Local {
+            name,
+            depth: None,
+            is_captured: false,
+        });
+    }
+
+    fn mark_initialized(&mut self) {
+        if self.current.scope_depth == 0 {
+            return;
+        }
+
+        let depth = self.current.scope_depth;
+        if let Some(local) = self.current.locals.last_mut() {
+            local.depth = Some(depth);
+        }
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        if self.current.scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit_constant_op(Op::DefineGlobal, global);
+    }
+
+    fn statement(&mut self) {
+        if self.parser.match_token(TokenType::Print) {
+            self.print_statement();
+        } else if self.parser.match_token(TokenType::For) {
+            self.for_statement();
+        } else if self.parser.match_token(TokenType::If) {
+            self.if_statement();
+        } else if self.parser.match_token(TokenType::Return) {
+            self.return_statement();
+        } else if self.parser.match_token(TokenType::While) {
+            self.while_statement();
+        } else if self.parser.match_token(TokenType::LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
+
+    fn block(&mut self) {
+        while !self.parser.check(TokenType::RightBrace) && !self.parser.check(TokenType::EOF) {
+            self.declaration();
+        }
+
+        self.parser.consume(TokenType::RightBrace, "Expect '}' after block.");
+    }
+
+    fn begin_scope(&mut self) {
+        self.current.scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.current.scope_depth -= 1;
+
+        while let Some(local) = self.current.locals.last() {
+            if local.depth.is_some_and(|depth| depth <= self.current.scope_depth) {
+                break;
+            }
+
+            if local.is_captured {
+                self.emit_op(Op::CloseUpvalue);
+            } else {
+                self.emit_op(Op::Pop);
+            }
+            self.current.locals.pop();
+        }
+    }
+
+    fn return_statement(&mut self) {
+        if self.current.function_type == FunctionType::Script {
+            self.parser.error("Can't return from top-level code.");
+        }
+
+        if self.parser.match_token(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.current.function_type == FunctionType::Initializer {
+                self.parser.error("Can't return a value from an initializer.");
+            }
+
+            self.expression();
+            self.parser.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            self.emit_op(Op::Return);
+        }
+    }
+
+    fn if_statement(&mut self) {
+        self.parser.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
         self.expression();
-        self.parser.consume(TokenType::EOF, "Expect end of expression");
-        self.end();
+        self.parser.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let then_jump = self.emit_jump(Op::JumpIfFalse);
+        self.emit_op(Op::Pop);
+        self.statement();
+
+        let else_jump = self.emit_jump(Op::Jump);
+
+        self.patch_jump(then_jump);
+        self.emit_op(Op::Pop);
+
+        if self.parser.match_token(TokenType::Else) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.current_chunk().code.len();
+        self.parser.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.parser.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(Op::JumpIfFalse);
+        self.emit_op(Op::Pop);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_op(Op::Pop);
+    }
+
+    fn for_statement(&mut self) {
+        self.begin_scope();
+        self.parser.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        let mut loop_variable = None;
+        if self.parser.match_token(TokenType::Semicolon) {
+            // No initializer.
+        } else if self.parser.match_token(TokenType::Var) {
+            let slot = self.current.locals.len();
+            self.var_declaration();
+            if self.current.locals.len() > slot {
+                loop_variable = Some(slot);
+            }
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.current_chunk().code.len();
+        let mut exit_jump = None;
+        if !self.parser.match_token(TokenType::Semicolon) {
+            self.expression();
+            self.parser.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+
+            // Jump out of the loop if the condition is false.
+            exit_jump = Some(self.emit_jump(Op::JumpIfFalse));
+            self.emit_op(Op::Pop); // Condition.
+        }
+
+        if !self.parser.match_token(TokenType::RightParen) {
+            let body_jump = self.emit_jump(Op::Jump);
+            let increment_start = self.current_chunk().code.len();
+            self.expression();
+            self.emit_op(Op::Pop);
+            self.parser.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        // Give the body its own copy of the loop variable, so closures created
+        // in it capture that iteration's value rather than one shared by all.
+        let mut body_variable = None;
+        if let Some(outer) = loop_variable {
+            self.begin_scope();
+            let name = self.current.locals[outer].name;
+            self.emit_op(Op::GetLocal);
+            self.emit_byte(outer as u8);
+            self.add_local(name);
+            self.mark_initialized();
+            body_variable = Some((outer, self.current.locals.len() - 1));
+        }
+
+        self.statement();
+
+        if let Some((outer, inner)) = body_variable {
+            // Carry any change the body made over to the increment.
+            self.emit_op(Op::GetLocal);
+            self.emit_byte(inner as u8);
+            self.emit_op(Op::SetLocal);
+            self.emit_byte(outer as u8);
+            self.emit_op(Op::Pop);
+            self.end_scope();
+        }
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_op(Op::Pop); // Condition.
+        }
+
+        self.end_scope();
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.parser.consume(TokenType::Semicolon, "Expect ';' after value.");
+        self.emit_op(Op::Print);
+    }
 
-        return !self.parser.had_error;
+    fn expression_statement(&mut self) {
+        self.expression();
+        self.parser.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        self.emit_op(Op::Pop);
     }
 
-    fn grouping(&mut self) {
+    fn grouping(&mut self, _can_assign: bool) {
         self.expression();
         self.parser.consume(TokenType::RightParen, "Expect ')' after expression.");
     }
@@ -111,41 +651,95 @@ impl<'a> Compiler<'a> {
         self.parse_precedence(Precedence::Assignment);
     }
 
-    fn unary(&mut self) {
-        let operator_type = self.parser.previous.token_type;
+    fn unary(&mut self, _can_assign: bool) {
+        let operator = self.parser.previous;
 
         // Compile the operand.
         self.parse_precedence(Precedence::Unary);
 
         // Emit the operator instruction.
-        match operator_type {
-            TokenType::Minus => self.emit_op(Op::Negate),
-            TokenType::Bang => self.emit_op(Op::Not),
+        match operator.token_type {
+            TokenType::Minus => self.emit_op_at(Op::Negate, &operator),
+            TokenType::Bang => self.emit_op_at(Op::Not, &operator),
             _ => return, // Unreachable.
         }
     }
 
-    fn binary(&mut self) {
-        let operator_type = self.parser.previous.token_type;
-        let rule = get_rule(operator_type);
+    fn binary(&mut self, _can_assign: bool) {
+        let operator = self.parser.previous;
+        let rule = get_rule(operator.token_type);
         self.parse_precedence(rule.precedence.next());
 
-        match operator_type {
-            TokenType::BangEqual    => self.emit_ops(Op::Equal, Op::Not),
-            TokenType::EqualEqual   => self.emit_op(Op::Equal),
-            TokenType::Greater      => self.emit_op(Op::Greater),
-            TokenType::GreaterEqual => self.emit_ops(Op::Less, Op::Not),
-            TokenType::Less         => self.emit_op(Op::Less),
-            TokenType::LessEqual    => self.emit_ops(Op::Greater, Op::Not),
-            TokenType::Plus         => self.emit_op(Op::Add),
-            TokenType::Minus        => self.emit_op(Op::Subtract),
-            TokenType::Star         => self.emit_op(Op::Multiply),
-            TokenType::Slash        => self.emit_op(Op::Divide),
+        match operator.token_type {
+            TokenType::BangEqual    => self.emit_ops_at(Op::Equal, Op::Not, &operator),
+            TokenType::EqualEqual   => self.emit_op_at(Op::Equal, &operator),
+            TokenType::Greater      => self.emit_op_at(Op::Greater, &operator),
+            TokenType::GreaterEqual => self.emit_ops_at(Op::Less, Op::Not, &operator),
+            TokenType::Less         => self.emit_op_at(Op::Less, &operator),
+            TokenType::LessEqual    => self.emit_ops_at(Op::Greater, Op::Not, &operator),
+            TokenType::Plus         => self.emit_op_at(Op::Add, &operator),
+            TokenType::Minus        => self.emit_op_at(Op::Subtract, &operator),
+            TokenType::Star         => self.emit_op_at(Op::Multiply, &operator),
+            TokenType::Slash        => self.emit_op_at(Op::Divide, &operator),
+            TokenType::Percent      => self.emit_op_at(Op::Modulo, &operator),
             _ => return, // Unreachable.
         }
     }
 
-    fn literal(&mut self) {
+    fn call(&mut self, _can_assign: bool) {
+        let paren = self.parser.previous;
+        let arg_count = self.argument_list();
+        self.emit_op_at(Op::Call, &paren);
+        self.emit_byte_at(arg_count, &paren);
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: usize = 0;
+        if !self.parser.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == 255 {
+                    self.parser.error("Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+
+                if !self.parser.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.parser.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        return arg_count as u8;
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.parser.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let property = self.parser.previous;
+        let name = self.identifier_constant(&property);
+
+        if can_assign && self.parser.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_constant_op_at(Op::SetProperty, name, &property);
+        } else if let Some((op, operator)) = self.match_compound_assignment(can_assign) {
+            // Load through a copy of the receiver, keeping the original for
+            // the store, so the receiver expression only runs once.
+            self.emit_op_at(Op::Dup, &property);
+            self.emit_constant_op_at(Op::GetProperty, name, &property);
+            self.expression();
+            self.emit_op_at(op, &operator);
+            self.emit_constant_op_at(Op::SetProperty, name, &property);
+        } else if self.parser.match_token(TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            self.emit_constant_op_at(Op::Invoke, name, &property);
+            self.emit_byte_at(arg_count, &property);
+        } else {
+            let start = self.current_chunk().code.len();
+            self.emit_constant_op(Op::GetProperty, name);
+            self.mark_target(start, TargetKind::Property { name }, &property);
+        }
+    }
+
+    fn literal(&mut self, _can_assign: bool) {
         match self.parser.previous.token_type {
             TokenType::Nil   => self.emit_op(Op::Nil),
             TokenType::False => self.emit_op(Op::False),
@@ -154,100 +748,466 @@ impl<'a> Compiler<'a> {
         }
     }
 
-    fn string(&mut self) {
+    fn string(&mut self, _can_assign: bool) {
         let token = self.parser.previous;
-        let mut vec = vec![0; token.length - 2];
-        vec.clone_from_slice(&self.parser.scanner.source[token.start + 1..token.start + token.length - 1]);
-        let str = String::from_utf8(vec).unwrap();
+        // Strip the quotes.
+        let body = &self.parser.scanner.source[token.start + 1..token.start + token.length - 1];
+        let str = match unescape(body) {
+            Ok(str) => str,
+            Err(error) => {
+                let start = token.start + 1 + error.offset;
+                let at = self.parser.scanner.token_within(&token, start, error.length);
+                self.parser.error_at(&at, error.message);
+                return;
+            }
+        };
+        let str = self.intern(str);
+
+        self.emit_constant(Value::Object(Obj::LString(str)));
+    }
+
+    fn and(&mut self, _can_assign: bool) {
+        let end_jump = self.emit_jump(Op::JumpIfFalse);
+
+        self.emit_op(Op::Pop);
+        self.parse_precedence(Precedence::And);
+
+        self.patch_jump(end_jump);
+    }
+
+    fn or(&mut self, _can_assign: bool) {
+        let else_jump = self.emit_jump(Op::JumpIfFalse);
+        let end_jump = self.emit_jump(Op::Jump);
+
+        self.patch_jump(else_jump);
+        self.emit_op(Op::Pop);
+
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
+
+    fn super_(&mut self, _can_assign: bool) {
+        match self.classes.last() {
+            None => self.parser.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.parser.error("Can't use 'super' in a class with no superclass.")
+            }
+            _ => {}
+        }
+
+        self.parser.consume(TokenType::Dot, "Expect '.' after 'super'.");
+        self.parser.consume(TokenType::Identifier, "Expect superclass method name.");
+        let method = self.parser.previous;
+        let name = self.identifier_constant(&method);
+
+        self.named_variable(&Token::synthetic(TokenType::This), false);
+        if self.parser.match_token(TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            self.named_variable(&Token::synthetic(TokenType::Super), false);
+            self.emit_constant_op_at(Op::SuperInvoke, name, &method);
+            self.emit_byte_at(arg_count, &method);
+        } else {
+            self.named_variable(&Token::synthetic(TokenType::Super), false);
+            self.emit_constant_op(Op::GetSuper, name);
+        }
+    }
+
+    fn this(&mut self, _can_assign: bool) {
+        if self.classes.is_empty() {
+            self.parser.error("Can't use 'this' outside of a class.");
+            return;
+        }
+
+        self.variable(false);
+        self.last_target = None; // `this` can't be assigned to.
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        let name = self.parser.previous;
+        self.named_variable(&name, can_assign);
+    }
+
+    fn named_variable(&mut self, name: &Token, can_assign: bool) {
+        // Local slots and upvalue indexes always fit in a byte, so only
+        // globals can need the long form.
+        let (get_op, set_op, arg) = if let Some(slot) = self.current.resolve_local(&mut self.parser, name) {
+            (Op::GetLocal, Op::SetLocal, slot as usize)
+        } else if let Some(index) = self.current.resolve_upvalue(&mut self.parser, name) {
+            (Op::GetUpvalue, Op::SetUpvalue, index as usize)
+        } else {
+            (Op::GetGlobal, Op::SetGlobal, self.identifier_constant(name))
+        };
+
+        if can_assign && self.parser.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_constant_op_at(set_op, arg, name);
+        } else if let Some((op, operator)) = self.match_compound_assignment(can_assign) {
+            self.emit_constant_op_at(get_op, arg, name);
+            self.expression();
+            self.emit_op_at(op, &operator);
+            self.emit_constant_op_at(set_op, arg, name);
+        } else {
+            let start = self.current_chunk().code.len();
+            self.emit_constant_op(get_op, arg);
+            self.mark_target(start, TargetKind::Variable { set_op, arg }, name);
+        }
+    }
 
-        self.emit_constant(Constant::String(str));
+    // Consumes a compound assignment operator like `+=`, returning the
+    // arithmetic it performs and the operator token.
+    fn match_compound_assignment(&mut self, can_assign: bool) -> Option<(Op, Token)> {
+        if !can_assign {
+            return None;
+        }
+
+        let op = match self.parser.current.token_type {
+            TokenType::PlusEqual    => Op::Add,
+            TokenType::MinusEqual   => Op::Subtract,
+            TokenType::StarEqual    => Op::Multiply,
+            TokenType::SlashEqual   => Op::Divide,
+            TokenType::PercentEqual => Op::Modulo,
+            _ => return None,
+        };
+        self.parser.advance();
+        return Some((op, self.parser.previous));
+    }
+
+    fn prefix_increment(&mut self, _can_assign: bool) {
+        let operator = self.parser.previous;
+        self.parse_precedence(Precedence::Unary);
+        self.increment(&operator, false);
+    }
+
+    fn postfix_increment(&mut self, _can_assign: bool) {
+        let operator = self.parser.previous;
+        self.increment(&operator, true);
+    }
+
+    // Rewrites the load the operand just compiled to into code that adds or
+    // subtracts one and stores the result. Leaves the old value for postfix
+    // operators and the new one for prefix.
+    fn increment(&mut self, operator: &Token, postfix: bool) {
+        let (op, message) = match operator.token_type {
+            TokenType::PlusPlus => (Op::Add, "Invalid increment target."),
+            _ => (Op::Subtract, "Invalid decrement target."),
+        };
+
+        // Only a load that nothing else has been emitted after will do.
+        // Anything else, like `++(a + b)`, isn't a place to store into.
+        let end = self.current_chunk().code.len();
+        let target = match self.last_target.take() {
+            Some(target) if target.end == end => target,
+            _ => {
+                self.parser.error_at(operator, message);
+                return;
+            }
+        };
+
+        match target.kind {
+            TargetKind::Variable { set_op, arg } => {
+                if postfix {
+                    self.emit_op(Op::Dup);
+                }
+                self.emit_constant(Value::Number(1.0));
+                self.emit_op_at(op, operator);
+                self.emit_constant_op_at(set_op, arg, &target.name);
+            }
+            TargetKind::Property { name } => {
+                // Load again, this time keeping the receiver for the store.
+                self.current_chunk().truncate(target.start);
+                self.emit_op_at(Op::Dup, &target.name);
+                self.emit_constant_op_at(Op::GetProperty, name, &target.name);
+                if postfix {
+                    // [receiver, old] -> [old, receiver, old]
+                    self.emit_op(Op::Swap);
+                    self.emit_op(Op::Over);
+                }
+                self.emit_constant(Value::Number(1.0));
+                self.emit_op_at(op, operator);
+                self.emit_constant_op_at(Op::SetProperty, name, &target.name);
+            }
+        }
+
+        if postfix {
+            self.emit_op(Op::Pop);
+        }
+    }
+
+    fn mark_target(&mut self, start: usize, kind: TargetKind, name: &Token) {
+        let end = self.current_chunk().code.len();
+        self.last_target = Some(Target {
+            kind,
+            name: *name,
+            start,
+            end,
+        });
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) {
         self.parser.advance();
+        let prec_byte = ToPrimitive::to_u8(&precedence).unwrap();
+        let can_assign = prec_byte <= ToPrimitive::to_u8(&Precedence::Assignment).unwrap();
+
         let prefix_rule = get_rule(self.parser.previous.token_type).prefix;
         match prefix_rule {
-            None => self.parser.error("Expect expression."),
-            Some(prefix_rule) => prefix_rule(self),
+            None => {
+                self.parser.error("Expect expression.");
+                return;
+            }
+            Some(prefix_rule) => prefix_rule(self, can_assign),
         }
 
-        let prec_byte = ToPrimitive::to_u8(&precedence).unwrap();
-
         while prec_byte <= ToPrimitive::to_u8(&get_rule(self.parser.current.token_type).precedence).unwrap() {
             self.parser.advance();
             let infix_rule = get_rule(self.parser.previous.token_type).infix;
-            match infix_rule {
-                Some(infix_rule) => infix_rule(self),
-                _ => {}
+            if let Some(infix_rule) = infix_rule {
+                infix_rule(self, can_assign);
             }
         }
+
+        if can_assign
+            && (self.parser.match_token(TokenType::Equal) || self.match_compound_assignment(can_assign).is_some())
+        {
+            self.parser.error("Invalid assignment target.");
+        }
     }
 
-    fn end(&mut self) {
+    fn end_compiler(&mut self) -> (Function, Vec<Capture>) {
         self.emit_return();
-        if cfg!(feature = "DEBUG_PRINT_CODE") {
-            if !self.parser.had_error {
-                self.compiling_chunk.disassemble("code");
+
+        let (mut function, upvalues) = match self.current.enclosing.take() {
+            Some(enclosing) => {
+                let compiler = std::mem::replace(&mut self.current, enclosing);
+                (compiler.function, compiler.upvalues)
+            }
+            None => (
+                std::mem::replace(&mut self.current.function, Function::new(None)),
+                std::mem::take(&mut self.current.upvalues),
+            ),
+        };
+        function.chunk.source = Rc::clone(&self.source);
+
+        if cfg!(feature = "DEBUG_PRINT_CODE") && !self.parser.had_error {
+            match function.name {
+                Some(name) => function.chunk.disassemble(&name.obj().chars),
+                None => function.chunk.disassemble("<script>"),
             }
         }
+
+        return (function, upvalues);
     }
 
-    fn number(&mut self) {
-        let value: f64 = self
-            .parser
-            .scanner
-            .get_lexeme(&self.parser.previous)
-            .parse()
-            .unwrap();
-        self.emit_constant(Constant::Number(value));
+    fn number(&mut self, _can_assign: bool) {
+        // The scanner has already checked the literal is well formed.
+        let lexeme = self.parser.scanner.get_lexeme(&self.parser.previous).replace('_', "");
+        let value = match lexeme.get(..2) {
+            Some("0x") | Some("0X") => parse_radix(&lexeme[2..], 16),
+            Some("0b") | Some("0B") => parse_radix(&lexeme[2..], 2),
+            _ => lexeme.parse().unwrap(),
+        };
+        self.emit_constant(Value::Number(value));
     }
 
     fn emit_return(&mut self) {
+        if self.current.function_type == FunctionType::Initializer {
+            // Initializers implicitly return `this`.
+            self.emit_op(Op::GetLocal);
+            self.emit_byte(0);
+        } else {
+            self.emit_op(Op::Nil);
+        }
         self.emit_op(Op::Return);
     }
 
     fn emit_op(&mut self, op: Op) {
-        self.compiling_chunk.write_op(op, self.parser.previous.line);
+        let previous = self.parser.previous;
+        self.emit_op_at(op, &previous);
+    }
+
+    // Attributes the instruction to `token` rather than the last one consumed.
+    fn emit_op_at(&mut self, op: Op, token: &Token) {
+        self.current_chunk().write_op(op, token.span());
     }
 
-    fn emit_ops(&mut self, op1: Op, op2: Op) {
-        self.compiling_chunk.write_op(op1, self.parser.previous.line);
-        self.compiling_chunk.write_op(op2, self.parser.previous.line);
+    fn emit_ops_at(&mut self, op1: Op, op2: Op, token: &Token) {
+        self.emit_op_at(op1, token);
+        self.emit_op_at(op2, token);
     }
 
     fn emit_byte(&mut self, byte: u8) {
-        self.compiling_chunk.write(byte, self.parser.previous.line);
+        let previous = self.parser.previous;
+        self.emit_byte_at(byte, &previous);
+    }
+
+    fn emit_byte_at(&mut self, byte: u8, token: &Token) {
+        self.current_chunk().write(byte, token.span());
     }
 
     fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
-        self.compiling_chunk.write(byte1, self.parser.previous.line);
-        self.compiling_chunk.write(byte2, self.parser.previous.line);
+        self.emit_byte(byte1);
+        self.emit_byte(byte2);
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_op(Op::Loop);
+
+        let offset = self.current_chunk().code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.parser.error("Loop body too large.");
+        }
+
+        self.emit_bytes(((offset >> 8) & 0xff) as u8, (offset & 0xff) as u8);
+    }
+
+    fn emit_jump(&mut self, op: Op) -> usize {
+        self.emit_op(op);
+        self.emit_bytes(0xff, 0xff);
+        return self.current_chunk().code.len() - 2;
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        // The jump lands after the last load, so that load no longer ends
+        // the expression on every path, as in `++(a or b)`.
+        self.last_target = None;
+
+        // -2 to adjust for the bytecode for the jump offset itself.
+        let jump = self.current_chunk().code.len() - offset - 2;
+
+        if jump > u16::MAX as usize {
+            self.parser.error("Too much code to jump over.");
+        }
+
+        self.current_chunk().code[offset] = ((jump >> 8) & 0xff) as u8;
+        self.current_chunk().code[offset + 1] = (jump & 0xff) as u8;
     }
 
-    fn emit_constant(&mut self, constant: Constant) {
-        self.emit_op(Op::Constant);
+    fn emit_constant(&mut self, constant: Value) {
         let constant = self.make_constant(constant);
-        self.emit_byte(constant);
+        self.emit_constant_op(Op::Constant, constant);
+    }
+
+    // Emits `op` with a one byte operand, or its long form with a 24-bit
+    // big-endian operand when the index doesn't fit in a byte.
+    fn emit_constant_op(&mut self, op: Op, index: usize) {
+        let previous = self.parser.previous;
+        self.emit_constant_op_at(op, index, &previous);
+    }
+
+    fn emit_constant_op_at(&mut self, op: Op, index: usize, token: &Token) {
+        if index <= u8::MAX as usize {
+            self.emit_op_at(op, token);
+            self.emit_byte_at(index as u8, token);
+        } else {
+            self.emit_op_at(op.long(), token);
+            self.emit_byte_at(((index >> 16) & 0xff) as u8, token);
+            self.emit_byte_at(((index >> 8) & 0xff) as u8, token);
+            self.emit_byte_at((index & 0xff) as u8, token);
+        }
     }
 
-    fn make_constant(&mut self, constant: Constant) -> u8 {
-        let constant = self.compiling_chunk.add_constant(constant);
-        if constant > u8::MAX as usize {
+    fn make_constant(&mut self, value: Value) -> usize {
+        let key = ConstantKey::for_value(value);
+        if let Some(&constant) = key.and_then(|key| self.current.constants.get(&key)) {
+            return constant;
+        }
+
+        let constant = self.current_chunk().add_constant(value);
+        if constant >= MAX_CONSTANTS {
             self.parser.error("Too many constants in one chunk");
             return 0;
         }
 
-        return constant as u8;
+        if let Some(key) = key {
+            self.current.constants.insert(key, constant);
+        }
+        return constant;
+    }
+}
+
+impl FunctionCompiler {
+    fn new(function_type: FunctionType, name: Option<Ref<LoxString>>) -> FunctionCompiler {
+        let mut locals = Vec::with_capacity(UINT8_COUNT);
+
+        // Slot zero holds the function being called, or the receiver for
+        // methods so that `this` resolves to it like any other local.
+        let slot_zero = match function_type {
+            FunctionType::Function | FunctionType::Script => Token::null(),
+            FunctionType::Initializer | FunctionType::Method => Token::synthetic(TokenType::This),
+        };
+        locals.push(Local {
+            name: slot_zero,
+            depth: Some(0),
+            is_captured: false,
+        });
+
+        FunctionCompiler {
+            enclosing: None,
+            function: Function::new(name),
+            function_type,
+            locals,
+            upvalues: Vec::with_capacity(UINT8_COUNT),
+            scope_depth: 0,
+            constants: HashMap::new(),
+        }
+    }
+
+    fn resolve_local(&self, parser: &mut Parser, name: &Token) -> Option<u8> {
+        let found = self
+            .locals
+            .iter()
+            .rposition(|local| parser.identifiers_equal(name, &local.name));
+
+        match found {
+            Some(slot) => {
+                if self.locals[slot].depth.is_none() {
+                    parser.error("Can't read local variable in its own initializer.");
+                }
+                Some(slot as u8)
+            }
+            None => None,
+        }
+    }
+
+    fn resolve_upvalue(&mut self, parser: &mut Parser, name: &Token) -> Option<u8> {
+        let enclosing = self.enclosing.as_deref_mut()?;
+
+        if let Some(local) = enclosing.resolve_local(parser, name) {
+            enclosing.locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(parser, local, true));
+        }
+
+        if let Some(upvalue) = enclosing.resolve_upvalue(parser, name) {
+            return Some(self.add_upvalue(parser, upvalue, false));
+        }
+
+        return None;
+    }
+
+    fn add_upvalue(&mut self, parser: &mut Parser, index: u8, is_local: bool) -> u8 {
+        let capture = Capture { index, is_local };
+        if let Some(existing) = self.upvalues.iter().position(|&upvalue| upvalue == capture) {
+            return existing as u8;
+        }
+
+        if self.upvalues.len() == UINT8_COUNT {
+            parser.error("Too many closure variables in function.");
+            return 0;
+        }
+
+        self.upvalues.push(capture);
+        self.function.upvalue_count = self.upvalues.len();
+        return (self.upvalues.len() - 1) as u8;
     }
 }
 
 impl Parser<'_> {
-    fn new<'s>(scanner: Scanner) -> Parser {
+    fn new(scanner: Scanner<'_>) -> Parser<'_> {
         Parser {
             scanner: scanner,
             current: Token::null(),
             previous: Token::null(),
             had_error: false,
+            diagnostics: Vec::new(),
             panic_mode: false,
         }
     }
@@ -261,7 +1221,55 @@ impl Parser<'_> {
                 break;
             }
 
-            self.error_at_current("Unknown token");
+            if let Some(error) = self.current.error {
+                self.error_at_current(&error.to_string());
+            }
+        }
+    }
+
+    fn identifiers_equal(&self, a: &Token, b: &Token) -> bool {
+        // Keywords used as local names are synthesized without a lexeme.
+        if a.token_type != TokenType::Identifier || b.token_type != TokenType::Identifier {
+            return a.token_type == b.token_type;
+        }
+
+        self.scanner.get_lexeme(a) == self.scanner.get_lexeme(b)
+    }
+
+    fn check(&self, token_type: TokenType) -> bool {
+        self.current.token_type == token_type
+    }
+
+    fn match_token(&mut self, token_type: TokenType) -> bool {
+        if !self.check(token_type) {
+            return false;
+        }
+
+        self.advance();
+        return true;
+    }
+
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+
+        while self.current.token_type != TokenType::EOF {
+            if self.previous.token_type == TokenType::Semicolon {
+                return;
+            }
+
+            match self.current.token_type {
+                TokenType::Class  |
+                TokenType::Fun    |
+                TokenType::Var    |
+                TokenType::For    |
+                TokenType::If     |
+                TokenType::While  |
+                TokenType::Print  |
+                TokenType::Return => return,
+                _ => {} // Do nothing.
+            }
+
+            self.advance();
         }
     }
 
@@ -290,17 +1298,142 @@ impl Parser<'_> {
         }
         self.panic_mode = true;
 
-        eprint!("[line {}] Error", token.line);
+        let at = match token.token_type {
+            TokenType::EOF => Some("end".to_string()),
+            TokenType::Error => None,
+            _ => Some(format!("'{}'", self.scanner.get_lexeme(token))),
+        };
 
-        if token.token_type == TokenType::EOF {
-            eprint!(" at end");
-        } else if token.token_type == TokenType::Error {
-            // Nothing.
-        } else {
-            eprint!(" at '{}'", self.scanner.get_lexeme(token));
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            message: message.to_string(),
+            line: token.line as usize,
+            column: Some(token.column as usize),
+            span: Some(token.start..token.start + token.length),
+            source_line: Some(source_line(self.scanner.source, token.start)),
+            at,
+            stack_trace: None,
+        });
+        self.had_error = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(value: &Value) -> f64 {
+        match value {
+            Value::Number(n) => *n,
+            _ => panic!("Expected a number constant."),
         }
+    }
 
-        eprintln!(": {}", message);
-        self.had_error = true;
+    #[test]
+    fn repeated_literals_share_a_constant() {
+        let mut vm = VM::new();
+        let source = "var a = 1; print 1; print \"s\" + \"s\"; print a + 1.0;";
+        let function = match Compiler::new(source, &mut vm).compile() {
+            Ok(function) => function,
+            Err(diagnostics) => panic!("{:?}", diagnostics),
+        };
+
+        // "a", 1 and "s".
+        assert_eq!(function.obj().chunk.constants.len(), 3);
+    }
+
+    #[test]
+    fn zero_and_negative_zero_stay_distinct() {
+        let mut vm = VM::new();
+        let mut compiler = Compiler::new("", &mut vm);
+
+        let zero = compiler.make_constant(Value::Number(0.0));
+        let negative_zero = compiler.make_constant(Value::Number(-0.0));
+        assert_ne!(zero, negative_zero);
+        assert_eq!(compiler.make_constant(Value::Number(0.0)), zero);
+        assert_eq!(compiler.make_constant(Value::Number(-0.0)), negative_zero);
+
+        let constants = &compiler.current_chunk().constants;
+        assert!(number(&constants[zero]).is_sign_positive());
+        assert!(number(&constants[negative_zero]).is_sign_negative());
+    }
+
+    #[test]
+    fn nan_constants_are_still_nan() {
+        let mut vm = VM::new();
+        let mut compiler = Compiler::new("", &mut vm);
+
+        let nan = compiler.make_constant(Value::Number(f64::NAN));
+        assert_eq!(compiler.make_constant(Value::Number(f64::NAN)), nan);
+        assert!(number(&compiler.current_chunk().constants[nan]).is_nan());
+
+        // A NaN with a different payload is a different constant.
+        let other_nan = f64::from_bits(f64::NAN.to_bits() ^ 1);
+        assert_ne!(compiler.make_constant(Value::Number(other_nan)), nan);
+    }
+
+    // Every instruction in `function` and the functions nested in it,
+    // decoded the way the disassembler steps through them.
+    fn instructions(function: &Function, ops: &mut Vec<Op>) {
+        let chunk = &function.chunk;
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            ops.push(num_traits::FromPrimitive::from_u8(chunk.code[offset]).unwrap());
+            offset = chunk.disassemble_instruction(offset);
+        }
+
+        for constant in chunk.constants.iter() {
+            if let Value::Object(Obj::Function(nested)) = constant {
+                instructions(nested.obj(), ops);
+            }
+        }
+    }
+
+    #[test]
+    fn constants_past_256_use_long_instructions() {
+        // Each global adds its name and its number, pushing the script's
+        // constants well past a byte.
+        let mut source = std::string::String::new();
+        for i in 0..300 {
+            source.push_str(&format!("var g{} = {};\n", i, i));
+        }
+
+        // So does a method with 300 numbers in it.
+        source.push_str("class Base { get() { return 1; } }\n");
+        source.push_str("class Derived < Base {\n get() {\n var n = 0;\n");
+        for i in 0..300 {
+            source.push_str(&format!(" n = {}.5;\n", i));
+        }
+        source.push_str(" var method = super.get;\n return method() + super.get();\n }\n}\n");
+        source.push_str("g299 = g298; var d = Derived(); d.f = 1; print d.f; print d.get();\n");
+
+        let mut vm = VM::new();
+        let function = match Compiler::new(&source, &mut vm).compile() {
+            Ok(function) => function,
+            Err(diagnostics) => panic!("{:?}", diagnostics),
+        };
+
+        let mut ops = Vec::new();
+        instructions(function.obj(), &mut ops);
+        let long = [
+            Op::ConstantLong,
+            Op::GetGlobalLong,
+            Op::DefineGlobalLong,
+            Op::SetGlobalLong,
+            Op::GetPropertyLong,
+            Op::SetPropertyLong,
+            Op::GetSuperLong,
+            Op::InvokeLong,
+            Op::SuperInvokeLong,
+            Op::ClosureLong,
+            Op::ClassLong,
+            Op::MethodLong,
+        ];
+        for op in long.iter() {
+            assert!(op.is_long());
+            assert!(ops.contains(op), "no {:?} emitted", op);
+        }
+        assert!(!Op::Constant.is_long());
+        assert_eq!(Op::Invoke.long(), Op::InvokeLong);
     }
 }
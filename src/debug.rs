@@ -1,6 +1,8 @@
 use num_traits::FromPrimitive;
 
 use crate::chunk::*;
+use crate::object::*;
+use crate::value::*;
 
 impl Chunk {
     pub fn disassemble(&self, name: &str) {
@@ -15,29 +17,69 @@ impl Chunk {
     pub fn disassemble_instruction(&self, offset: usize) -> usize {
         print!("{:04} ", offset);
 
-        if offset > 0 && self.lines[offset] == self.lines[offset - 1] {
+        if offset > 0 && self.get_line(offset) == self.get_line(offset - 1) {
             print!("   | ");
         } else {
-            print!("{:4} ", self.lines[offset]);
+            print!("{:4} ", self.get_line(offset));
         }
 
         let instruction = self.code[offset];
         match FromPrimitive::from_u8(instruction) {
-            Some(Op::Constant) => constant_instruction("OP_CONSTANT", self, offset),
-            Some(Op::Nil)      => simple_instruction("OP_NIL", offset),
-            Some(Op::False)    => simple_instruction("OP_FALSE", offset),
-            Some(Op::True)     => simple_instruction("OP_TRUE", offset),
-            Some(Op::Equal)    => simple_instruction("OP_EQUAL", offset),
-            Some(Op::Greater)  => simple_instruction("OP_GREATER", offset),
-            Some(Op::Less)     => simple_instruction("OP_LESS", offset),
-            Some(Op::Add)      => simple_instruction("OP_ADD", offset),
-            Some(Op::Subtract) => simple_instruction("OP_SUBTRACT", offset),
-            Some(Op::Multiply) => simple_instruction("OP_MULTIPLY", offset),
-            Some(Op::Divide)   => simple_instruction("OP_DIVIDE", offset),
-            Some(Op::Not)      => simple_instruction("OP_NOT", offset),
-            Some(Op::Negate)   => simple_instruction("OP_NEGATE", offset),
-            Some(Op::Return)   => simple_instruction("OP_RETURN", offset),
-            None               => { println!("Unknown opcode: {}", instruction); offset + 1 }
+            Some(Op::Constant)     => constant_instruction("OP_CONSTANT", self, offset),
+            Some(Op::Nil)          => simple_instruction("OP_NIL", offset),
+            Some(Op::False)        => simple_instruction("OP_FALSE", offset),
+            Some(Op::True)         => simple_instruction("OP_TRUE", offset),
+            Some(Op::Pop)          => simple_instruction("OP_POP", offset),
+            Some(Op::GetLocal)     => byte_instruction("OP_GET_LOCAL", self, offset),
+            Some(Op::SetLocal)     => byte_instruction("OP_SET_LOCAL", self, offset),
+            Some(Op::GetGlobal)    => constant_instruction("OP_GET_GLOBAL", self, offset),
+            Some(Op::DefineGlobal) => constant_instruction("OP_DEFINE_GLOBAL", self, offset),
+            Some(Op::SetGlobal)    => constant_instruction("OP_SET_GLOBAL", self, offset),
+            Some(Op::GetUpvalue)   => byte_instruction("OP_GET_UPVALUE", self, offset),
+            Some(Op::SetUpvalue)   => byte_instruction("OP_SET_UPVALUE", self, offset),
+            Some(Op::GetProperty)  => constant_instruction("OP_GET_PROPERTY", self, offset),
+            Some(Op::SetProperty)  => constant_instruction("OP_SET_PROPERTY", self, offset),
+            Some(Op::GetSuper)     => constant_instruction("OP_GET_SUPER", self, offset),
+            Some(Op::Equal)        => simple_instruction("OP_EQUAL", offset),
+            Some(Op::Greater)      => simple_instruction("OP_GREATER", offset),
+            Some(Op::Less)         => simple_instruction("OP_LESS", offset),
+            Some(Op::Add)          => simple_instruction("OP_ADD", offset),
+            Some(Op::Subtract)     => simple_instruction("OP_SUBTRACT", offset),
+            Some(Op::Multiply)     => simple_instruction("OP_MULTIPLY", offset),
+            Some(Op::Divide)       => simple_instruction("OP_DIVIDE", offset),
+            Some(Op::Not)          => simple_instruction("OP_NOT", offset),
+            Some(Op::Negate)       => simple_instruction("OP_NEGATE", offset),
+            Some(Op::Print)        => simple_instruction("OP_PRINT", offset),
+            Some(Op::Jump)         => jump_instruction("OP_JUMP", 1, self, offset),
+            Some(Op::JumpIfFalse)  => jump_instruction("OP_JUMP_IF_FALSE", 1, self, offset),
+            Some(Op::Loop)         => jump_instruction("OP_LOOP", -1, self, offset),
+            Some(Op::Call)         => byte_instruction("OP_CALL", self, offset),
+            Some(Op::Invoke)       => invoke_instruction("OP_INVOKE", self, offset),
+            Some(Op::SuperInvoke)  => invoke_instruction("OP_SUPER_INVOKE", self, offset),
+            Some(Op::Closure)      => closure_instruction("OP_CLOSURE", self, offset),
+            Some(Op::CloseUpvalue) => simple_instruction("OP_CLOSE_UPVALUE", offset),
+            Some(Op::Return)       => simple_instruction("OP_RETURN", offset),
+            Some(Op::Class)        => constant_instruction("OP_CLASS", self, offset),
+            Some(Op::Inherit)      => simple_instruction("OP_INHERIT", offset),
+            Some(Op::Method)       => constant_instruction("OP_METHOD", self, offset),
+            Some(Op::Modulo)       => simple_instruction("OP_MODULO", offset),
+            Some(Op::Dup)          => simple_instruction("OP_DUP", offset),
+            Some(Op::Over)         => simple_instruction("OP_OVER", offset),
+            Some(Op::Swap)         => simple_instruction("OP_SWAP", offset),
+
+            Some(Op::ConstantLong)     => constant_instruction("OP_CONSTANT_LONG", self, offset),
+            Some(Op::GetGlobalLong)    => constant_instruction("OP_GET_GLOBAL_LONG", self, offset),
+            Some(Op::DefineGlobalLong) => constant_instruction("OP_DEFINE_GLOBAL_LONG", self, offset),
+            Some(Op::SetGlobalLong)    => constant_instruction("OP_SET_GLOBAL_LONG", self, offset),
+            Some(Op::GetPropertyLong)  => constant_instruction("OP_GET_PROPERTY_LONG", self, offset),
+            Some(Op::SetPropertyLong)  => constant_instruction("OP_SET_PROPERTY_LONG", self, offset),
+            Some(Op::GetSuperLong)     => constant_instruction("OP_GET_SUPER_LONG", self, offset),
+            Some(Op::InvokeLong)       => invoke_instruction("OP_INVOKE_LONG", self, offset),
+            Some(Op::SuperInvokeLong)  => invoke_instruction("OP_SUPER_INVOKE_LONG", self, offset),
+            Some(Op::ClosureLong)      => closure_instruction("OP_CLOSURE_LONG", self, offset),
+            Some(Op::ClassLong)        => constant_instruction("OP_CLASS_LONG", self, offset),
+            Some(Op::MethodLong)       => constant_instruction("OP_METHOD_LONG", self, offset),
+            None                   => { println!("Unknown opcode: {}", instruction); offset + 1 }
         }
     }
 }
@@ -47,12 +89,73 @@ fn simple_instruction(name: &str, offset: usize) -> usize {
     return offset + 1;
 }
 
+fn byte_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let slot = chunk.code[offset + 1];
+    println!("{:16} {:4}", name, slot);
+    return offset + 2;
+}
+
+fn jump_instruction(name: &str, sign: i32, chunk: &Chunk, offset: usize) -> usize {
+    let jump = ((chunk.code[offset + 1] as u16) << 8) | chunk.code[offset + 2] as u16;
+    let target = offset as i32 + 3 + sign * jump as i32;
+    println!("{:16} {:4} -> {}", name, offset, target);
+    return offset + 3;
+}
+
+// Decodes the constant index of the instruction at `offset`, returning it
+// along with the offset of the byte after it.
+fn constant_index(chunk: &Chunk, offset: usize) -> (usize, usize) {
+    let op: Op = FromPrimitive::from_u8(chunk.code[offset]).unwrap();
+    if !op.is_long() {
+        return (chunk.code[offset + 1] as usize, offset + 2);
+    }
+
+    let index = ((chunk.code[offset + 1] as usize) << 16)
+        | ((chunk.code[offset + 2] as usize) << 8)
+        | chunk.code[offset + 3] as usize;
+    return (index, offset + 4);
+}
+
+fn closure_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let (constant, mut offset) = constant_index(chunk, offset);
+
+    print!("{:16} {:4} ", name, constant);
+    chunk.constants[constant].print();
+    println!();
+
+    let upvalue_count = match chunk.constants[constant] {
+        Value::Object(Obj::Function(function)) => function.obj().upvalue_count,
+        _ => 0,
+    };
+
+    for _ in 0..upvalue_count {
+        let is_local = chunk.code[offset];
+        let index = chunk.code[offset + 1];
+        let kind = if is_local == 1 { "local" } else { "upvalue" };
+        println!("{:04}    |                     {} {}", offset, kind, index);
+        offset += 2;
+    }
+
+    return offset;
+}
+
+fn invoke_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let (constant, offset) = constant_index(chunk, offset);
+    let arg_count = chunk.code[offset];
+
+    print!("{:16} ({} args) {:4} '", name, arg_count, constant);
+    chunk.constants[constant].print();
+    println!("'");
+
+    return offset + 1;
+}
+
 fn constant_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
-    let constant = chunk.code[offset + 1];
+    let (constant, offset) = constant_index(chunk, offset);
 
     print!("{:16} {:4} '", name, constant);
-    chunk.constants[constant as usize].print();
+    chunk.constants[constant].print();
     println!("'");
 
-    return offset + 2;
+    return offset;
 }
@@ -0,0 +1,130 @@
+use std::fmt;
+use std::ops::Range;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub line: usize,
+    pub column: Option<usize>,       // 1-based, when the error points at a token
+    pub span: Option<Range<usize>>,  // byte offsets into the source
+    pub source_line: Option<String>, // the full line the span starts on
+    pub at: Option<String>,          // what a compile error was reported at, e.g. "'foo'" or "end"
+    pub stack_trace: Option<Vec<StackFrame>>, // innermost call first; runtime errors only
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct StackFrame {
+    pub function: Option<String>, // None for top-level script code
+    pub line: usize,
+}
+
+/// Receives every diagnostic the VM produces, in the order they occur.
+pub trait Reporter {
+    fn report(&mut self, diagnostic: &Diagnostic);
+}
+
+/// The default reporter. Writes diagnostics to stderr in the same format
+/// as clox.
+pub struct StderrReporter;
+
+impl Reporter for StderrReporter {
+    fn report(&mut self, diagnostic: &Diagnostic) {
+        eprint!("{}", diagnostic);
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => f.write_str("Error"),
+            Severity::Warning => f.write_str("Warning"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.stack_trace {
+            Some(stack_trace) => {
+                writeln!(f, "{}", self.message)?;
+                self.write_snippet(f)?;
+                for frame in stack_trace {
+                    writeln!(f, "{}", frame)?;
+                }
+            }
+            None => {
+                write!(f, "[line {}] {}", self.line, self.severity)?;
+                if let Some(at) = &self.at {
+                    write!(f, " at {}", at)?;
+                }
+                writeln!(f, ": {}", self.message)?;
+                self.write_snippet(f)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Diagnostic {
+    // Prints the offending line with the span underlined, like
+    //
+    //     print a + ;
+    //               ^
+    fn write_snippet(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (source_line, column) = match (&self.source_line, self.column) {
+            (Some(source_line), Some(column)) => (source_line, column),
+            _ => return Ok(()),
+        };
+
+        writeln!(f, "    {}", source_line)?;
+
+        // Columns count characters. Reuse tabs from the source line so the
+        // caret lines up.
+        let indent: String = source_line
+            .chars()
+            .take(column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        // The span is in bytes, so find how many characters of the line
+        // it covers.
+        let start = source_line
+            .char_indices()
+            .nth(column - 1)
+            .map_or(source_line.len(), |(offset, _)| offset);
+        let length = self.span.as_ref().map_or(1, |span| span.len());
+        let end = (start + length).min(source_line.len());
+        let covered = source_line
+            .char_indices()
+            .filter(|&(offset, _)| offset >= start && offset < end)
+            .count();
+        writeln!(f, "    {}^{}", indent, "~".repeat(covered.saturating_sub(1)))
+    }
+}
+
+/// Returns the line of `source` containing the byte at `offset`, without
+/// its line terminator.
+pub(crate) fn source_line(source: &str, offset: usize) -> String {
+    let offset = offset.min(source.len());
+    let start = source[..offset].rfind('\n').map_or(0, |newline| newline + 1);
+    let end = source[offset..].find('\n').map_or(source.len(), |newline| offset + newline);
+
+    source[start..end].trim_end_matches('\r').to_string()
+}
+
+impl fmt::Display for StackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "[line {}] in {}()", self.line, name),
+            None => write!(f, "[line {}] in script", self.line),
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use crate::object::*;
+use crate::value::Value;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+
+/// A Lox value held by Rust code. Any object it refers to is registered
+/// as a root with the VM it came from, so the collector keeps it alive for
+/// as long as the handle exists.
+pub struct Handle {
+    value: Value,
+    root: Option<Root>, // None for values that aren't objects
+}
+
+// An entry in the root set, removed again when the handle is dropped.
+struct Root {
+    id: u64,
+    roots: Rc<RefCell<Roots>>,
+}
+
+// The objects Rust holds handles to. Shared between a VM and its handles
+// so that dropping a handle doesn't need the VM.
+pub(crate) struct Roots {
+    values: HashMap<u64, Value>,
+    next_id: u64,
+    heap_alive: bool, // cleared when the VM and its heap are dropped
+}
+
+impl Roots {
+    pub(crate) fn new() -> Rc<RefCell<Roots>> {
+        Rc::new(RefCell::new(Roots {
+            values: HashMap::new(),
+            next_id: 0,
+            heap_alive: true,
+        }))
+    }
+
+    pub(crate) fn values(&self) -> impl Iterator<Item = Value> + '_ {
+        self.values.values().copied()
+    }
+
+    pub(crate) fn heap_dropped(&mut self) {
+        self.heap_alive = false;
+    }
+}
+
+impl Handle {
+    pub(crate) fn new(value: Value, roots: &Rc<RefCell<Roots>>) -> Handle {
+        let root = match value {
+            Value::Object(_) => {
+                let mut set = roots.borrow_mut();
+                let id = set.next_id;
+                set.next_id += 1;
+                set.values.insert(id, value);
+                Some(Root {
+                    id,
+                    roots: Rc::clone(roots),
+                })
+            }
+            _ => None,
+        };
+
+        Handle { value, root }
+    }
+
+    // Returns the raw value for use inside the VM owning `roots`. An object
+    // from another VM would dangle once that VM is dropped, so refuse it.
+    pub(crate) fn value_for(&self, roots: &Rc<RefCell<Roots>>) -> Value {
+        if let Some(root) = &self.root {
+            assert!(Rc::ptr_eq(&root.roots, roots), "Handle belongs to a different VM.");
+        }
+        self.value
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(self.value, Value::Nil)
+    }
+
+    // The object this refers to, or an error if the VM that owned it has
+    // been dropped and taken the object with it.
+    fn object(&self) -> Result<Option<Obj>, String> {
+        match (self.value, &self.root) {
+            (Value::Object(obj), Some(root)) => {
+                if !root.roots.borrow().heap_alive {
+                    return Err("The VM this value came from has been dropped.".to_string());
+                }
+                Ok(Some(obj))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+impl Clone for Handle {
+    fn clone(&self) -> Handle {
+        match &self.root {
+            Some(root) => Handle::new(self.value, &root.roots),
+            None => Handle {
+                value: self.value,
+                root: None,
+            },
+        }
+    }
+}
+
+impl Drop for Root {
+    fn drop(&mut self) {
+        self.roots.borrow_mut().values.remove(&self.id);
+    }
+}
+
+impl PartialEq for Handle {
+    fn eq(&self, other: &Handle) -> bool {
+        self.value == other.value
+    }
+}
+
+impl fmt::Debug for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.value {
+            Value::Nil => f.write_str("nil"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Object(_) => match self.object() {
+                Ok(Some(Obj::LString(s))) => write!(f, "{:?}", s.obj().chars),
+                _ => f.write_str("<object>"),
+            },
+        }
+    }
+}
+
+impl From<f64> for Handle {
+    fn from(n: f64) -> Handle {
+        Handle {
+            value: Value::Number(n),
+            root: None,
+        }
+    }
+}
+
+impl From<bool> for Handle {
+    fn from(b: bool) -> Handle {
+        Handle {
+            value: Value::Bool(b),
+            root: None,
+        }
+    }
+}
+
+impl From<()> for Handle {
+    fn from(_: ()) -> Handle {
+        Handle {
+            value: Value::Nil,
+            root: None,
+        }
+    }
+}
+
+impl From<&Handle> for Handle {
+    fn from(handle: &Handle) -> Handle {
+        handle.clone()
+    }
+}
+
+impl TryFrom<&Handle> for f64 {
+    type Error = String;
+
+    fn try_from(handle: &Handle) -> Result<f64, String> {
+        match handle.value {
+            Value::Number(n) => Ok(n),
+            _ => Err("Expected a number.".to_string()),
+        }
+    }
+}
+
+impl TryFrom<&Handle> for bool {
+    type Error = String;
+
+    fn try_from(handle: &Handle) -> Result<bool, String> {
+        match handle.value {
+            Value::Bool(b) => Ok(b),
+            _ => Err("Expected a boolean.".to_string()),
+        }
+    }
+}
+
+impl TryFrom<&Handle> for String {
+    type Error = String;
+
+    fn try_from(handle: &Handle) -> Result<String, String> {
+        match handle.object()? {
+            Some(Obj::LString(s)) => Ok(s.obj().chars.clone()),
+            _ => Err("Expected a string.".to_string()),
+        }
+    }
+}
+
+impl TryFrom<Handle> for f64 {
+    type Error = String;
+
+    fn try_from(handle: Handle) -> Result<f64, String> {
+        f64::try_from(&handle)
+    }
+}
+
+impl TryFrom<Handle> for bool {
+    type Error = String;
+
+    fn try_from(handle: Handle) -> Result<bool, String> {
+        bool::try_from(&handle)
+    }
+}
+
+impl TryFrom<Handle> for String {
+    type Error = String;
+
+    fn try_from(handle: Handle) -> Result<String, String> {
+        String::try_from(&handle)
+    }
+}
@@ -0,0 +1,23 @@
+// The code base follows clox closely, so keep its explicit `return` style.
+#![allow(clippy::needless_return, clippy::redundant_field_names, clippy::upper_case_acronyms)]
+// Diagnostics only exist on the error path, so their size doesn't matter.
+#![allow(clippy::result_large_err)]
+// num-derive 0.3 expands to impls inside anonymous consts.
+#![allow(non_local_definitions)]
+
+mod chunk;
+mod compiler;
+mod debug;
+mod diagnostic;
+mod handle;
+mod memory;
+mod object;
+mod scanner;
+mod table;
+mod value;
+mod vm;
+
+pub use diagnostic::{Diagnostic, Reporter, Severity, StackFrame, StderrReporter};
+pub use handle::Handle;
+pub use object::NativeFn;
+pub use vm::{InterpretResult, VM};
@@ -1,14 +1,5 @@
-mod chunk;
-mod compiler;
-mod debug;
-mod memory;
-mod object;
-mod scanner;
-mod value;
-mod vm;
-
 use std::io::Write;
-use vm::*;
+use rlox::{InterpretResult, VM};
 
 fn main() {
     let mut vm = VM::new();
@@ -31,11 +22,11 @@ fn repl(vm: &mut VM) {
         std::io::stdout().lock().flush().unwrap();
 
         match std::io::stdin().read_line(&mut line) {
-            Ok(_) => vm.interpret(&line),
-            Err(_) => {
-                print!("\n");
+            Ok(0) | Err(_) => {
+                println!();
                 break;
             }
+            Ok(_) => vm.interpret(&line),
         };
 
         line.clear();
@@ -43,12 +34,18 @@ fn repl(vm: &mut VM) {
 }
 
 fn run_file(path: &String, vm: &mut VM) {
-    let source = std::fs::read_to_string(path).unwrap();
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("Could not read file \"{}\": {}", path, error);
+            std::process::exit(74);
+        }
+    };
     let result = vm.interpret(&source);
 
     match result {
-        InterpretResult::CompileError => std::process::exit(65),
-        InterpretResult::RuntimeError => std::process::exit(70),
+        InterpretResult::CompileError(_) => std::process::exit(65),
+        InterpretResult::RuntimeError(_) => std::process::exit(70),
         _ => {}
     }
 }
@@ -1,43 +1,256 @@
-use std::any::Any;
+use crate::object::*;
+use crate::table::*;
+use crate::value::*;
+
+use std::cell::Cell;
+use std::hash::{Hash, Hasher};
+
+const GC_HEAP_GROW_FACTOR: usize = 2;
+const GC_INITIAL_THRESHOLD: usize = 1024 * 1024;
 
 pub struct Heap {
-    values: Vec<Box<dyn Any>>,
-    strings: Vec<String>,
+    objects: Vec<Box<dyn Allocation>>,
+    strings: Table, // weak: the collector drops unmarked entries
+    gray_stack: Vec<Obj>,
+    bytes_allocated: usize,
+    next_gc: usize,
+}
+
+// Every managed object lives in its own box so that it never moves while
+// a Ref to it exists. The header carries the mark bit.
+struct GcBox<T> {
+    marked: Cell<bool>,
+    value: T,
+}
+
+/// Memory an object owns outside its box, like a string's characters or an
+/// instance's fields. It counts towards the heap size along with the box.
+pub trait OwnedBytes {
+    fn owned_bytes(&self) -> usize {
+        0
+    }
+}
+
+trait Allocation {
+    fn is_marked(&self) -> bool;
+    fn unmark(&self);
+    fn size(&self) -> usize;
+}
+
+impl<T: OwnedBytes> Allocation for GcBox<T> {
+    fn is_marked(&self) -> bool {
+        self.marked.get()
+    }
+
+    fn unmark(&self) {
+        self.marked.set(false);
+    }
+
+    // What the object weighs now, which is what it was charged at
+    // allocation plus any growth charged through `grow_table` since.
+    fn size(&self) -> usize {
+        std::mem::size_of::<GcBox<T>>() + self.value.owned_bytes()
+    }
 }
 
 impl Heap {
     pub fn new() -> Heap {
         Heap {
-            values: Vec::new(),
-            strings: Vec::new(),
+            objects: Vec::new(),
+            strings: Table::new(),
+            gray_stack: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: GC_INITIAL_THRESHOLD,
         }
     }
 
-    pub fn manage<T: 'static>(&mut self, obj: T) -> Ref<T> {
-        let boxed = Box::new(obj);
+    pub fn manage<T: OwnedBytes + 'static>(&mut self, obj: T) -> Ref<T> {
+        self.allocate(obj)
+    }
+
+    pub fn manage_str(&mut self, str: String) -> Ref<LoxString> {
+        let hash = hash_string(&str);
+        if let Some(interned) = self.strings.find_string(&str, hash) {
+            return interned;
+        }
+
+        let r = self.allocate(LoxString { chars: str, hash });
+        // The intern table is never freed, so its growth is charged for good.
+        let before = self.strings.allocated_bytes();
+        self.strings.set(r, Value::Nil);
+        self.bytes_allocated += self.strings.allocated_bytes() - before;
+        r
+    }
+
+    /// Runs `update` on a table owned by a managed object, charging any
+    /// growth of the table to the heap.
+    pub fn grow_table<R>(&mut self, table: &mut Table, update: impl FnOnce(&mut Table) -> R) -> R {
+        let before = table.allocated_bytes();
+        let result = update(table);
+        self.bytes_allocated += table.allocated_bytes() - before;
+        result
+    }
+
+    pub fn find_string(&self, chars: &str) -> Option<Ref<LoxString>> {
+        self.strings.find_string(chars, hash_string(chars))
+    }
+
+    fn allocate<T: OwnedBytes + 'static>(&mut self, obj: T) -> Ref<T> {
+        let boxed = Box::new(GcBox {
+            marked: Cell::new(false),
+            value: obj,
+        });
+        let size = boxed.size();
         let r = Ref { obj: &*boxed };
-        self.values.push(boxed);
+        self.objects.push(boxed);
+        self.bytes_allocated += size;
+
+        if cfg!(feature = "DEBUG_LOG_GC") {
+            println!("{:p} allocate {}", r.obj, size);
+        }
+
         r
     }
 
-    pub fn manage_str(&mut self, str: String) -> Ref<String> {
-        let item = self.strings.iter().find(|&s| *s == str);
-        match item {
-            Some(s) => Ref { obj: s },
-            None    => {
-                self.strings.push(str);
-                Ref { obj: &self.strings[self.strings.len() - 1] }
+    /// True once enough has been allocated since the last collection that
+    /// the owner of the roots should call `collect_garbage` at its next
+    /// safe point. The heap never collects on its own.
+    pub fn should_collect(&self) -> bool {
+        cfg!(feature = "DEBUG_STRESS_GC") || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if let Value::Object(obj) = value {
+            self.mark_object(obj);
+        }
+    }
+
+    pub fn mark_object(&mut self, obj: Obj) {
+        let newly_marked = match obj {
+            Obj::LString(s) => s.mark(),
+            Obj::Function(f) => f.mark(),
+            Obj::Closure(c) => c.mark(),
+            Obj::Upvalue(u) => u.mark(),
+            Obj::Class(c) => c.mark(),
+            Obj::Instance(i) => i.mark(),
+            Obj::BoundMethod(b) => b.mark(),
+            Obj::Native(n) => n.mark(),
+        };
+
+        if newly_marked {
+            if cfg!(feature = "DEBUG_LOG_GC") {
+                print!("mark ");
+                obj.print_obj();
+                println!();
+            }
+
+            self.gray_stack.push(obj);
+        }
+    }
+
+    pub fn mark_function(&mut self, function: &Function) {
+        if let Some(name) = function.name {
+            self.mark_object(Obj::LString(name));
+        }
+
+        for &constant in function.chunk.constants.iter() {
+            self.mark_value(constant);
+        }
+    }
+
+    pub fn mark_table(&mut self, table: &Table) {
+        for (key, value) in table.iter() {
+            self.mark_object(Obj::LString(key));
+            self.mark_value(value);
+        }
+    }
+
+    /// Traces everything reachable from the objects marked so far and frees
+    /// the rest. Callers must mark their roots first.
+    pub fn collect_garbage(&mut self) {
+        let before = self.bytes_allocated;
+        if cfg!(feature = "DEBUG_LOG_GC") {
+            println!("-- gc begin");
+        }
+
+        self.trace_references();
+        // The intern table holds strings weakly.
+        self.strings.retain_keys(|s| s.is_marked());
+        self.sweep();
+
+        self.next_gc = self.bytes_allocated * GC_HEAP_GROW_FACTOR;
+
+        if cfg!(feature = "DEBUG_LOG_GC") {
+            println!("-- gc end");
+            println!(
+                "   collected {} bytes (from {} to {}) next at {}",
+                before - self.bytes_allocated,
+                before,
+                self.bytes_allocated,
+                self.next_gc
+            );
+        }
+    }
+
+    fn trace_references(&mut self) {
+        while let Some(obj) = self.gray_stack.pop() {
+            self.blacken_object(obj);
+        }
+    }
+
+    fn blacken_object(&mut self, obj: Obj) {
+        match obj {
+            Obj::LString(_) | Obj::Native(_) => {}
+            Obj::Function(f) => self.mark_function(f.obj()),
+            Obj::Closure(c) => {
+                let closure = c.obj();
+                self.mark_object(Obj::Function(closure.function));
+                for &upvalue in closure.upvalues.iter() {
+                    self.mark_object(Obj::Upvalue(upvalue));
+                }
+            }
+            Obj::Upvalue(u) => {
+                // Open upvalues point at the stack, which is a root already.
+                if let Some(value) = u.obj().closed.get() {
+                    self.mark_value(value);
+                }
+            }
+            Obj::Class(c) => {
+                let class = c.obj();
+                self.mark_object(Obj::LString(class.name));
+                self.mark_table(&class.methods.borrow());
+            }
+            Obj::Instance(i) => {
+                let instance = i.obj();
+                self.mark_object(Obj::Class(instance.class));
+                self.mark_table(&instance.fields.borrow());
+            }
+            Obj::BoundMethod(b) => {
+                let bound = b.obj();
+                self.mark_value(bound.receiver);
+                self.mark_object(Obj::Closure(bound.method));
             }
         }
     }
 
-    pub fn clear(&mut self) {
-        self.values.clear();
+    fn sweep(&mut self) {
+        let mut freed = 0;
+        self.objects.retain(|object| {
+            if object.is_marked() {
+                object.unmark();
+                true
+            } else {
+                freed += object.size();
+                false
+            }
+        });
+
+        self.bytes_allocated -= freed;
     }
 }
 
 pub struct Ref<T> {
-    obj: *const T
+    obj: *const GcBox<T>
 }
 
 impl<T> Copy for Ref<T> {}
@@ -54,8 +267,70 @@ impl<T: PartialEq> PartialEq for Ref<T> {
     }
 }
 
+impl<T: Eq> Eq for Ref<T> {}
+
+impl<T: Hash> Hash for Ref<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.obj().hash(state);
+    }
+}
+
 impl<T> Ref<T> {
     pub fn obj(&self) -> &T {
-        unsafe { &*self.obj }
+        unsafe { &(*self.obj).value }
+    }
+
+    pub fn ptr_eq(&self, other: &Ref<T>) -> bool {
+        std::ptr::eq(self.obj, other.obj)
     }
-}
\ No newline at end of file
+
+    fn is_marked(&self) -> bool {
+        unsafe { (*self.obj).marked.get() }
+    }
+
+    // Returns false if the object was already marked.
+    fn mark(&self) -> bool {
+        unsafe { !(*self.obj).marked.replace(true) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    // Everything charged so far, worked out from scratch.
+    fn expected_bytes(heap: &Heap) -> usize {
+        let objects: usize = heap.objects.iter().map(|object| object.size()).sum();
+        objects + heap.strings.allocated_bytes()
+    }
+
+    #[test]
+    fn owned_buffers_count_towards_the_heap() {
+        let mut heap = Heap::new();
+        let name = heap.manage_str("x".repeat(10_000));
+        assert!(heap.bytes_allocated > 10_000);
+
+        let class = heap.manage(Class {
+            name,
+            methods: RefCell::new(Table::new()),
+        });
+        let instance = heap.manage(Instance {
+            class,
+            fields: RefCell::new(Table::new()),
+        });
+
+        let before = heap.bytes_allocated;
+        for i in 0..100 {
+            let key = heap.manage_str(format!("field{}", i));
+            heap.grow_table(&mut instance.obj().fields.borrow_mut(), |fields| fields.set(key, Value::Nil));
+        }
+        assert!(heap.bytes_allocated - before >= instance.obj().fields.borrow().allocated_bytes());
+        assert_eq!(heap.bytes_allocated, expected_bytes(&heap));
+
+        // Nothing is marked, so only the intern table is left.
+        heap.collect_garbage();
+        assert!(heap.objects.is_empty());
+        assert_eq!(heap.bytes_allocated, heap.strings.allocated_bytes());
+    }
+}
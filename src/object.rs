@@ -1,15 +1,181 @@
+use crate::chunk::*;
+use crate::handle::*;
 use crate::memory::*;
 use crate::object::Obj::*;
+use crate::table::*;
+use crate::value::*;
+use crate::vm::*;
 
-#[derive(Copy, Clone, PartialEq)]
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+#[derive(Copy, Clone)]
 pub enum Obj {
-    LString(Ref<String>),
+    LString(Ref<LoxString>),
+    Function(Ref<Function>),
+    Closure(Ref<Closure>),
+    Upvalue(Ref<Upvalue>),
+    Class(Ref<Class>),
+    Instance(Ref<Instance>),
+    BoundMethod(Ref<BoundMethod>),
+    Native(Ref<Native>),
+}
+
+pub struct LoxString {
+    pub chars: String,
+    pub hash: u32,
+}
+
+pub struct Function {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    pub name: Option<Ref<LoxString>>,
+}
+
+pub struct Closure {
+    pub function: Ref<Function>,
+    pub upvalues: Vec<Ref<Upvalue>>,
+}
+
+pub struct Upvalue {
+    pub location: usize,             // stack slot of the captured variable while open
+    pub closed: Cell<Option<Value>>, // Some once the variable has left the stack
+}
+
+pub struct Class {
+    pub name: Ref<LoxString>,
+    pub methods: RefCell<Table>,
+}
+
+pub struct Instance {
+    pub class: Ref<Class>,
+    pub fields: RefCell<Table>,
+}
+
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Ref<Closure>,
+}
+
+pub type NativeFn = fn(&mut VM, &[Handle]) -> Result<Handle, String>;
+
+pub struct Native {
+    pub arity: usize,
+    pub function: NativeFn,
 }
 
 impl Obj {
     pub fn print_obj(&self) {
         match self {
-            LString(s) => print!("\"{}\"", s.obj())
+            LString(s) => print!("{}", s.obj()),
+            Function(f) => f.obj().print(),
+            Closure(c) => c.obj().function.obj().print(),
+            Upvalue(_) => print!("upvalue"),
+            Class(c) => print!("{}", c.obj().name.obj()),
+            Instance(i) => print!("{} instance", i.obj().class.obj().name.obj()),
+            BoundMethod(b) => b.obj().method.obj().function.obj().print(),
+            Native(_) => print!("<native fn>"),
+        }
+    }
+}
+
+impl PartialEq for Obj {
+    fn eq(&self, other: &Obj) -> bool {
+        match (self, other) {
+            // Strings are interned, so equal contents means the same object.
+            (LString(a), LString(b)) => a.ptr_eq(b),
+            (Function(a), Function(b)) => a.ptr_eq(b),
+            (Closure(a), Closure(b)) => a.ptr_eq(b),
+            (Upvalue(a), Upvalue(b)) => a.ptr_eq(b),
+            (Class(a), Class(b)) => a.ptr_eq(b),
+            (Instance(a), Instance(b)) => a.ptr_eq(b),
+            (BoundMethod(a), BoundMethod(b)) => a.ptr_eq(b),
+            (Native(a), Native(b)) => a.ptr_eq(b),
+            _ => false,
         }
     }
-}
\ No newline at end of file
+}
+
+impl PartialEq for LoxString {
+    fn eq(&self, other: &LoxString) -> bool {
+        self.hash == other.hash && self.chars == other.chars
+    }
+}
+
+impl Eq for LoxString {}
+
+impl Hash for LoxString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u32(self.hash);
+    }
+}
+
+impl OwnedBytes for LoxString {
+    fn owned_bytes(&self) -> usize {
+        self.chars.capacity()
+    }
+}
+
+impl OwnedBytes for Function {
+    fn owned_bytes(&self) -> usize {
+        self.chunk.allocated_bytes()
+    }
+}
+
+impl OwnedBytes for Closure {
+    fn owned_bytes(&self) -> usize {
+        self.upvalues.capacity() * std::mem::size_of::<Ref<Upvalue>>()
+    }
+}
+
+impl OwnedBytes for Class {
+    fn owned_bytes(&self) -> usize {
+        self.methods.borrow().allocated_bytes()
+    }
+}
+
+impl OwnedBytes for Instance {
+    fn owned_bytes(&self) -> usize {
+        self.fields.borrow().allocated_bytes()
+    }
+}
+
+impl OwnedBytes for Upvalue {}
+impl OwnedBytes for BoundMethod {}
+impl OwnedBytes for Native {}
+
+impl fmt::Display for LoxString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.chars)
+    }
+}
+
+// FNV-1a
+pub fn hash_string(chars: &str) -> u32 {
+    let mut hash: u32 = 2166136261;
+    for &byte in chars.as_bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(16777619);
+    }
+    return hash;
+}
+
+impl Function {
+    pub fn new(name: Option<Ref<LoxString>>) -> Function {
+        Function {
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk::new(),
+            name,
+        }
+    }
+
+    pub fn print(&self) {
+        match self.name {
+            Some(name) => print!("<fn {}>", name.obj()),
+            None => print!("<script>"),
+        }
+    }
+}
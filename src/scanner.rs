@@ -1,13 +1,18 @@
 use num_derive::FromPrimitive;
 use num_derive::ToPrimitive;
-use std::convert::TryInto;
+use std::fmt;
 use std::iter::*;
+use std::str::CharIndices;
+use unicode_xid::UnicodeXID;
 
 pub struct Scanner<'a> {
-    pub source: &'a [u8],
+    pub source: &'a str,
     start: usize,
-    current: usize,
-    line: u16,
+    current: usize, // byte offset, always on a character boundary
+    line: u32,
+    column: u32, // of the character at `current`
+    start_line: u32,
+    start_column: u32,
 }
 
 #[derive(Copy, Clone)]
@@ -15,7 +20,48 @@ pub struct Token {
     pub token_type: TokenType,
     pub start: usize,
     pub length: usize,
-    pub line: u16,
+    pub line: u32,   // where the token starts, for tokens spanning lines
+    pub column: u32, // 1-based, counting characters rather than bytes
+    pub error: Option<ScanError>, // set on Error tokens
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ScanError {
+    UnexpectedCharacter(char),
+    UnterminatedString,
+    UnterminatedComment,
+    InvalidHexDigit,
+    InvalidBinaryDigit,
+    MissingHexDigits,
+    MissingBinaryDigits,
+    MissingExponent,
+    MisplacedUnderscore,
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScanError::UnexpectedCharacter(c) => write!(f, "Unexpected character '{}'.", c.escape_debug()),
+            ScanError::UnterminatedString  => f.write_str("Unterminated string."),
+            ScanError::UnterminatedComment => f.write_str("Unterminated block comment."),
+            ScanError::InvalidHexDigit     => f.write_str("Invalid hex digit."),
+            ScanError::InvalidBinaryDigit  => f.write_str("Invalid binary digit."),
+            ScanError::MissingHexDigits    => f.write_str("Expect hex digits after '0x'."),
+            ScanError::MissingBinaryDigits => f.write_str("Expect binary digits after '0b'."),
+            ScanError::MissingExponent     => f.write_str("Expect digits in exponent."),
+            ScanError::MisplacedUnderscore => f.write_str("Underscores in numbers must be between digits."),
+        }
+    }
+}
+
+// Where in the source a token came from, as recorded for each byte of
+// compiled code.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Span {
+    pub start: u32,
+    pub length: u32,
+    pub line: u32,
+    pub column: u32,
 }
 
 impl Token {
@@ -25,6 +71,30 @@ impl Token {
             start: 0,
             length: 0,
             line: 0,
+            column: 0,
+            error: None,
+        }
+    }
+
+    // A token for a keyword the compiler refers to without it appearing in
+    // the source, such as the `this` held in a method's slot zero.
+    pub fn synthetic(token_type: TokenType) -> Token {
+        Token {
+            token_type,
+            start: 0,
+            length: 0,
+            line: 0,
+            column: 0,
+            error: None,
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        Span {
+            start: self.start as u32,
+            length: self.length as u32,
+            line: self.line,
+            column: self.column,
         }
     }
 }
@@ -34,13 +104,17 @@ pub enum TokenType {
     // Single-character tokens.
     LeftParen, RightParen,
     LeftBrace, RightBrace,
-    Comma, Dot, Minus, Plus,
-    Semicolon, Slash, Star,
+    Comma, Dot, Semicolon,
     // One or two character tokens.
     Bang, BangEqual,
     Equal, EqualEqual,
     Greater, GreaterEqual,
     Less, LessEqual,
+    Minus, MinusEqual, MinusMinus,
+    Plus, PlusEqual, PlusPlus,
+    Slash, SlashEqual,
+    Star, StarEqual,
+    Percent, PercentEqual,
     // Literals.
     Identifier, String, Number,
     // Keywords.
@@ -53,25 +127,30 @@ pub enum TokenType {
   }
 
 impl Scanner<'_> {
-    pub fn new(source: &String) -> Scanner {
+    pub fn new(source: &str) -> Scanner<'_> {
         Scanner {
-            source: source.as_bytes(),
+            source,
             start: 0,
             current: 0,
             line: 1,
+            column: 1,
+            start_line: 1,
+            start_column: 1,
         }
     }
 
     pub fn scan(&mut self) -> Token {
-        self.skip_whitespace();
-        self.start = self.current;
+        if let Err(error) = self.skip_whitespace() {
+            return error;
+        }
+        self.begin_token();
 
         if self.at_end() {
             return self.make_token(TokenType::EOF);
         }
 
-        let c = self.advance().try_into().unwrap();
-        if is_alpha(c) {
+        let c = self.advance();
+        if is_identifier_start(c) {
             return self.identifier();
         }
         if is_digit(c) {
@@ -86,10 +165,50 @@ impl Scanner<'_> {
             ';' => return self.make_token(TokenType::Semicolon),
             ',' => return self.make_token(TokenType::Comma),
             '.' => return self.make_token(TokenType::Dot),
-            '-' => return self.make_token(TokenType::Minus),
-            '+' => return self.make_token(TokenType::Plus),
-            '/' => return self.make_token(TokenType::Slash),
-            '*' => return self.make_token(TokenType::Star),
+            '-' => {
+                let tt = if self.match_char('=') {
+                    TokenType::MinusEqual
+                } else if self.match_char('-') {
+                    TokenType::MinusMinus
+                } else {
+                    TokenType::Minus
+                };
+                return self.make_token(tt);
+            }
+            '+' => {
+                let tt = if self.match_char('=') {
+                    TokenType::PlusEqual
+                } else if self.match_char('+') {
+                    TokenType::PlusPlus
+                } else {
+                    TokenType::Plus
+                };
+                return self.make_token(tt);
+            }
+            '/' => {
+                let tt = if self.match_char('=') {
+                    TokenType::SlashEqual
+                } else {
+                    TokenType::Slash
+                };
+                return self.make_token(tt);
+            }
+            '*' => {
+                let tt = if self.match_char('=') {
+                    TokenType::StarEqual
+                } else {
+                    TokenType::Star
+                };
+                return self.make_token(tt);
+            }
+            '%' => {
+                let tt = if self.match_char('=') {
+                    TokenType::PercentEqual
+                } else {
+                    TokenType::Percent
+                };
+                return self.make_token(tt);
+            }
             '!' => {
                 let tt = if self.match_char('=') {
                     TokenType::BangEqual
@@ -126,12 +245,26 @@ impl Scanner<'_> {
             _ => {}
         }
 
-        return self.error_token();
+        return self.error_token(ScanError::UnexpectedCharacter(c));
     }
 
-    fn advance(&mut self) -> u8 {
-        self.current += 1;
-        self.source[self.current - 1]
+    fn begin_token(&mut self) {
+        self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.column;
+    }
+
+    fn advance(&mut self) -> char {
+        let c = self.peek();
+        self.current += c.len_utf8();
+
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        c
     }
 
     fn match_char(&mut self, expected: char) -> bool {
@@ -143,50 +276,71 @@ impl Scanner<'_> {
             return false;
         }
 
-        self.current += 1;
+        self.advance();
         return true;
     }
 
-    fn skip_whitespace(&mut self) {
+    // Fails with an error token for an unterminated block comment.
+    fn skip_whitespace(&mut self) -> Result<(), Token> {
         loop {
             let c = self.peek();
             match c {
                 ' ' | '\r' | '\t' => {
                     self.advance();
-                    break;
                 }
                 '\n' => {
-                    self.line += 1;
                     self.advance();
                 }
-                '/' => {
-                    if self.peek_next() == '/' {
-                        // A comment goes until the end of the line.
-                        while self.peek() != '\n' && !self.at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+                '/' if self.peek_next() == '/' => {
+                    // A comment goes until the end of the line.
+                    while self.peek() != '\n' && !self.at_end() {
+                        self.advance();
                     }
                 }
-                _ => return,
+                '/' if self.peek_next() == '*' => self.block_comment()?,
+                _ => return Ok(()),
             }
         }
     }
 
-    fn peek(&self) -> char {
-        if self.at_end() {
-            return '\0';
+    // Block comments nest, so commenting out code that already contains
+    // one works.
+    fn block_comment(&mut self) -> Result<(), Token> {
+        // Errors point at the opening "/*".
+        self.begin_token();
+        self.advance();
+        self.advance();
+
+        let mut depth = 1;
+        while depth > 0 {
+            if self.at_end() {
+                return Err(self.error_token(ScanError::UnterminatedComment));
+            }
+
+            if self.peek() == '/' && self.peek_next() == '*' {
+                self.advance();
+                self.advance();
+                depth += 1;
+            } else if self.peek() == '*' && self.peek_next() == '/' {
+                self.advance();
+                self.advance();
+                depth -= 1;
+            } else {
+                self.advance();
+            }
         }
-        return self.source[self.current].try_into().unwrap();
+
+        return Ok(());
+    }
+
+    fn peek(&self) -> char {
+        return self.source[self.current..].chars().next().unwrap_or('\0');
     }
 
     fn peek_next(&self) -> char {
-        if self.at_end() {
-            return '\0';
-        } else {
-            return self.source[self.current + 1].try_into().unwrap();
-        }
+        let mut chars = self.source[self.current..].chars();
+        chars.next();
+        return chars.next().unwrap_or('\0');
     }
 
     fn at_end(&self) -> bool {
@@ -195,14 +349,21 @@ impl Scanner<'_> {
 
     fn string(&mut self) -> Token {
         while self.peek() != '"' && !self.at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+            // Step over the escaped character so `\"` doesn't end the
+            // string. The compiler decodes escapes.
+            if self.peek() == '\\' {
+                self.advance();
+                if self.at_end() {
+                    break;
+                }
             }
             self.advance();
         }
 
+        // Reported at the opening quote, since that's where the
+        // problem is rather than at the end of the file.
         if self.at_end() {
-            return self.error_token();
+            return self.error_token(ScanError::UnterminatedString);
         }
 
         // The closing quote.
@@ -211,8 +372,22 @@ impl Scanner<'_> {
     }
 
     fn number(&mut self) -> Token {
-        while is_digit(self.peek()) {
-            self.advance();
+        if self.source[self.start..].starts_with('0') {
+            match self.peek() {
+                'x' | 'X' => {
+                    self.advance();
+                    return self.radix_number(16, ScanError::MissingHexDigits, ScanError::InvalidHexDigit);
+                }
+                'b' | 'B' => {
+                    self.advance();
+                    return self.radix_number(2, ScanError::MissingBinaryDigits, ScanError::InvalidBinaryDigit);
+                }
+                _ => {}
+            }
+        }
+
+        if let Err(error) = self.digits(10) {
+            return self.error_token(error);
         }
 
         // Look for a fractional part.
@@ -220,29 +395,88 @@ impl Scanner<'_> {
             // Consume the ".".
             self.advance();
 
-            while is_digit(self.peek()) {
+            if let Err(error) = self.digits(10) {
+                return self.error_token(error);
+            }
+        }
+
+        // And an exponent.
+        if matches!(self.peek(), 'e' | 'E') {
+            self.advance();
+            if matches!(self.peek(), '+' | '-') {
                 self.advance();
             }
+
+            match self.digits(10) {
+                Ok(0) => return self.error_token(ScanError::MissingExponent),
+                Err(error) => return self.error_token(error),
+                Ok(_) => {}
+            }
         }
 
         return self.make_token(TokenType::Number);
     }
 
+    // Scans the digits of a `0x` or `0b` literal, after the prefix.
+    fn radix_number(&mut self, radix: u32, missing: ScanError, invalid: ScanError) -> Token {
+        let digits = self.digits(radix);
+
+        // Take the rest of something like `0xFG` so the error covers all of
+        // it, reporting a misplaced underscore before it if there was one.
+        if is_identifier_continue(self.peek()) {
+            while is_identifier_continue(self.peek()) {
+                self.advance();
+            }
+            return self.error_token(digits.err().unwrap_or(invalid));
+        }
+
+        match digits {
+            Ok(0) => return self.error_token(missing),
+            Err(error) => return self.error_token(error),
+            Ok(_) => return self.make_token(TokenType::Number),
+        }
+    }
+
+    // Consumes digits in `radix`, which may be separated by single
+    // underscores, and returns how many there were.
+    fn digits(&mut self, radix: u32) -> Result<usize, ScanError> {
+        let mut count = 0;
+        loop {
+            let c = self.peek();
+            if c.is_digit(radix) {
+                count += 1;
+            } else if c == '_' {
+                // Underscores only go between digits, so not straight after
+                // a prefix or an exponent either.
+                let previous = self.source[..self.current].chars().next_back();
+                let after_digit = previous.is_some_and(|p| p.is_digit(radix));
+                if !after_digit || !self.peek_next().is_digit(radix) {
+                    self.advance();
+                    return Err(ScanError::MisplacedUnderscore);
+                }
+            } else {
+                return Ok(count);
+            }
+            self.advance();
+        }
+    }
+
     fn identifier(&mut self) -> Token {
-        while is_alpha(self.peek()) || is_digit(self.peek()) {
+        while is_identifier_continue(self.peek()) {
             self.advance();
         }
         return self.make_token(self.identifier_type());
     }
 
     fn identifier_type(&self) -> TokenType {
-        match self.source[self.start].try_into().unwrap() {
+        let bytes = self.source.as_bytes();
+        match char::from(bytes[self.start]) {
             'a' => return self.check_keyword(1, "nd", TokenType::And),
             'c' => return self.check_keyword(1, "lass", TokenType::Class),
             'e' => return self.check_keyword(1, "lse", TokenType::Else),
             'f' => {
                 if self.current - self.start > 1 {
-                    match self.source[self.start + 1].try_into().unwrap() {
+                    match char::from(bytes[self.start + 1]) {
                         'a' => return self.check_keyword(2, "lse", TokenType::False),
                         'o' => return self.check_keyword(2, "r", TokenType::For),
                         'u' => return self.check_keyword(2, "n", TokenType::Fun),
@@ -259,7 +493,7 @@ impl Scanner<'_> {
             's' => return self.check_keyword(1, "uper", TokenType::Super),
             't' => {
                 if self.current - self.start > 1 {
-                    match self.source[self.start + 1].try_into().unwrap() {
+                    match char::from(bytes[self.start + 1]) {
                         'h' => return self.check_keyword(2, "is", TokenType::This),
                         'r' => return self.check_keyword(2, "ue", TokenType::True),
                         _   => return TokenType::Identifier,
@@ -276,7 +510,7 @@ impl Scanner<'_> {
     fn check_keyword(&self, start: usize, rest: &str, token_type: TokenType) -> TokenType {
         if self.current - self.start == start + rest.len() {
             for (i, c) in rest.chars().enumerate() {
-                let b = self.source[self.start + start + i];
+                let b = self.source.as_bytes()[self.start + start + i];
                 if c as u8 != b {
                     return TokenType::Identifier;
                 }
@@ -293,28 +527,381 @@ impl Scanner<'_> {
             token_type,
             start: self.start,
             length: self.current - self.start,
-            line: self.line,
+            line: self.start_line,
+            column: self.start_column,
+            error: None,
         }
     }
 
-    fn error_token(&self) -> Token {
+    fn error_token(&self, error: ScanError) -> Token {
         Token {
             token_type: TokenType::Error,
-            start: 0,
-            length: 0,
-            line: self.line,
+            start: self.start,
+            length: self.current - self.start,
+            line: self.start_line,
+            column: self.start_column,
+            error: Some(error),
+        }
+    }
+
+    /// Returns a token for `length` bytes at `start` inside `token`, so that
+    /// errors can point into the middle of a literal.
+    pub fn token_within(&self, token: &Token, start: usize, length: usize) -> Token {
+        let mut line = token.line;
+        let mut column = token.column;
+        for c in self.source[token.start..start].chars() {
+            if c == '\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+        }
+
+        Token {
+            token_type: token.token_type,
+            start,
+            length,
+            line,
+            column,
+            error: token.error,
         }
     }
 
     pub fn get_lexeme(&self, token: &Token) -> &str {
-        return std::str::from_utf8(&self.source[token.start..token.start + token.length]).unwrap();
+        return &self.source[token.start..token.start + token.length];
     }
 }
 
+pub struct EscapeError {
+    pub offset: usize, // into the string body
+    pub length: usize,
+    pub message: &'static str,
+}
+
+/// Decodes the escape sequences in the body of a string literal.
+pub fn unescape(body: &str) -> Result<String, EscapeError> {
+    let mut result = String::with_capacity(body.len());
+    let mut chars = body.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+
+        let escaped = match chars.next() {
+            Some((_, 'n')) => '\n',
+            Some((_, 't')) => '\t',
+            Some((_, 'r')) => '\r',
+            Some((_, '0')) => '\0',
+            Some((_, '\\')) => '\\',
+            Some((_, '"')) => '"',
+            Some((_, 'u')) => unicode_escape(start, &mut chars)?,
+            Some((i, c)) => {
+                return Err(EscapeError {
+                    offset: start,
+                    length: i + c.len_utf8() - start,
+                    message: "Invalid escape sequence.",
+                })
+            }
+            None => {
+                return Err(EscapeError {
+                    offset: start,
+                    length: 1,
+                    message: "Invalid escape sequence.",
+                })
+            }
+        };
+        result.push(escaped);
+    }
+
+    return Ok(result);
+}
+
+// Decodes the rest of a `\u{XXXX}` escape whose backslash is at `start`.
+fn unicode_escape(start: usize, chars: &mut Peekable<CharIndices>) -> Result<char, EscapeError> {
+    let error = |end: usize, message: &'static str| EscapeError {
+        offset: start,
+        length: end - start,
+        message,
+    };
+
+    let mut end = start + 2; // Past the "\u".
+    match chars.peek() {
+        Some(&(_, '{')) => {
+            chars.next();
+            end += 1;
+        }
+        _ => return Err(error(end, "Expect '{' after '\\u'.")),
+    }
+
+    let mut digits = 0;
+    let mut value: u32 = 0;
+    loop {
+        match chars.next() {
+            Some((i, '}')) => {
+                end = i + 1;
+                break;
+            }
+            Some((i, c)) => {
+                end = i + c.len_utf8();
+                let digit = match c.to_digit(16) {
+                    Some(digit) => digit,
+                    None => return Err(error(end, "Invalid hex digit in unicode escape.")),
+                };
+                digits += 1;
+                if digits > 6 {
+                    return Err(error(end, "Unicode escape must have 1 to 6 hex digits."));
+                }
+                value = value * 16 + digit;
+            }
+            None => return Err(error(end, "Unterminated unicode escape.")),
+        }
+    }
+
+    if digits == 0 {
+        return Err(error(end, "Unicode escape must have 1 to 6 hex digits."));
+    }
+
+    return char::from_u32(value).ok_or_else(|| error(end, "Invalid unicode code point."));
+}
+
 fn is_digit(c: char) -> bool {
-    c >= '0' && c <= '9'
+    c.is_ascii_digit()
 }
 
-fn is_alpha(c: char) -> bool {
-    (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || c == '_'
+fn is_identifier_start(c: char) -> bool {
+    c == '_' || c.is_xid_start()
+}
+
+fn is_identifier_continue(c: char) -> bool {
+    c.is_xid_continue()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TokenType::*;
+
+    fn scan_all(source: &str) -> Vec<Token> {
+        let mut scanner = Scanner::new(source);
+        let mut tokens = Vec::new();
+        loop {
+            let token = scanner.scan();
+            tokens.push(token);
+            if token.token_type == EOF {
+                return tokens;
+            }
+        }
+    }
+
+    fn types(source: &str) -> Vec<TokenType> {
+        scan_all(source).iter().map(|token| token.token_type).collect()
+    }
+
+    fn lexemes(source: &str) -> Vec<std::string::String> {
+        let scanner = Scanner::new(source);
+        scan_all(source)
+            .iter()
+            .map(|token| scanner.get_lexeme(token).to_string())
+            .collect()
+    }
+
+    fn single_error(source: &str) -> ScanError {
+        let tokens = scan_all(source);
+        let errors: Vec<ScanError> = tokens.iter().filter_map(|token| token.error).collect();
+        assert_eq!(errors.len(), 1, "errors in {:?}", source);
+        errors[0]
+    }
+
+    #[test]
+    fn punctuation() {
+        assert_eq!(
+            types("(){};,.-+*/"),
+            vec![
+                LeftParen, RightParen, LeftBrace, RightBrace, Semicolon, Comma, Dot, Minus, Plus, Star,
+                Slash, EOF
+            ]
+        );
+    }
+
+    #[test]
+    fn one_or_two_character_operators() {
+        assert_eq!(
+            types("! != = == > >= < <="),
+            vec![Bang, BangEqual, Equal, EqualEqual, Greater, GreaterEqual, Less, LessEqual, EOF]
+        );
+        assert_eq!(types("!==="), vec![BangEqual, EqualEqual, EOF]);
+        assert_eq!(types("<=="), vec![LessEqual, Equal, EOF]);
+        assert_eq!(
+            types("- -= -- + += ++ / /= * *= % %="),
+            vec![
+                Minus, MinusEqual, MinusMinus, Plus, PlusEqual, PlusPlus, Slash, SlashEqual, Star,
+                StarEqual, Percent, PercentEqual, EOF
+            ]
+        );
+        assert_eq!(types("a---b"), vec![Identifier, MinusMinus, Minus, Identifier, EOF]);
+        assert_eq!(types("+++="), vec![PlusPlus, PlusEqual, EOF]);
+    }
+
+    #[test]
+    fn keywords() {
+        let source = "and class else false for fun if nil or print return super this true var while";
+        assert_eq!(
+            types(source),
+            vec![
+                And, Class, Else, False, For, Fun, If, Nil, Or, Print, Return, Super, This, True, Var,
+                While, EOF
+            ]
+        );
+    }
+
+    #[test]
+    fn keyword_prefixes_and_extensions_are_identifiers() {
+        for source in ["fa", "fort", "thisx", "f", "t", "th", "tru", "classy", "an", "orr", "_and", "var1"] {
+            assert_eq!(types(source), vec![Identifier, EOF], "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn identifiers() {
+        assert_eq!(lexemes("foo _bar baz9 café 变量"), vec!["foo", "_bar", "baz9", "café", "变量", ""]);
+        assert_eq!(types("foo _bar baz9 café 变量"), vec![Identifier; 5].into_iter().chain([EOF]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(types("\"\" \"abc\""), vec![String, String, EOF]);
+        assert_eq!(lexemes("\"a \\\" b\""), vec!["\"a \\\" b\"", ""]);
+        assert_eq!(single_error("\"abc"), ScanError::UnterminatedString);
+        assert_eq!(single_error("\"abc\\\""), ScanError::UnterminatedString);
+    }
+
+    #[test]
+    fn numbers() {
+        let source = "0 123 1.5 0xFF 0b1010 1e-9 6.02E23 1_000_000";
+        assert_eq!(types(source), vec![Number; 8].into_iter().chain([EOF]).collect::<Vec<_>>());
+        assert_eq!(lexemes("1.5"), vec!["1.5", ""]);
+
+        // A trailing dot isn't part of the number.
+        assert_eq!(types("1."), vec![Number, Dot, EOF]);
+        assert_eq!(types("1.foo"), vec![Number, Dot, Identifier, EOF]);
+    }
+
+    #[test]
+    fn malformed_numbers() {
+        assert_eq!(single_error("0xFG"), ScanError::InvalidHexDigit);
+        assert_eq!(single_error("0x"), ScanError::MissingHexDigits);
+        assert_eq!(single_error("0b102"), ScanError::InvalidBinaryDigit);
+        assert_eq!(single_error("0b"), ScanError::MissingBinaryDigits);
+        assert_eq!(single_error("1e"), ScanError::MissingExponent);
+        assert_eq!(single_error("1e+"), ScanError::MissingExponent);
+        assert_eq!(single_error("1__0"), ScanError::MisplacedUnderscore);
+        assert_eq!(single_error("1_"), ScanError::MisplacedUnderscore);
+        assert_eq!(single_error("0x_FF"), ScanError::MisplacedUnderscore);
+        assert_eq!(single_error("0b_1"), ScanError::MisplacedUnderscore);
+        assert_eq!(single_error("1e_5"), ScanError::MisplacedUnderscore);
+        assert_eq!(single_error("1e+_5"), ScanError::MisplacedUnderscore);
+    }
+
+    #[test]
+    fn unexpected_characters() {
+        assert_eq!(single_error("@"), ScanError::UnexpectedCharacter('@'));
+        assert_eq!(single_error("a ☃ b"), ScanError::UnexpectedCharacter('☃'));
+        assert_eq!(lexemes("☃"), vec!["☃", ""]);
+    }
+
+    fn escape_error(body: &str) -> (usize, usize, &'static str) {
+        match unescape(body) {
+            Ok(result) => panic!("expected an error unescaping {:?}, got {:?}", body, result),
+            Err(error) => (error.offset, error.length, error.message),
+        }
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(unescape(r#"a\nb\tc\rd\0e\\f\"g"#).ok(), Some("a\nb\tc\rd\0e\\f\"g".to_string()));
+        assert_eq!(unescape(r"\u{41}\u{e9}\u{1F600}\u{10FFFF}").ok(), Some("Aé😀\u{10FFFF}".to_string()));
+        assert_eq!(unescape(r"\u{000041}").ok(), Some("A".to_string()));
+        assert_eq!(unescape("é∂ plain").ok(), Some("é∂ plain".to_string()));
+    }
+
+    #[test]
+    fn malformed_escapes() {
+        let digits = "Unicode escape must have 1 to 6 hex digits.";
+        let invalid = "Invalid escape sequence.";
+        assert_eq!(escape_error(r"\q"), (0, 2, invalid));
+        assert_eq!(escape_error("ab\\"), (2, 1, invalid));
+        assert_eq!(escape_error(r"\u{}"), (0, 4, digits));
+        assert_eq!(escape_error(r"\u{1234567}"), (0, 10, digits));
+        assert_eq!(escape_error(r"\u{D800}"), (0, 8, "Invalid unicode code point."));
+        assert_eq!(escape_error(r"\u{110000}"), (0, 10, "Invalid unicode code point."));
+        assert_eq!(escape_error(r"\u41"), (0, 2, "Expect '{' after '\\u'."));
+        assert_eq!(escape_error(r"\u{41"), (0, 5, "Unterminated unicode escape."));
+        assert_eq!(escape_error(r"\u{4G}"), (0, 5, "Invalid hex digit in unicode escape."));
+    }
+
+    #[test]
+    fn escape_errors_are_located_in_bytes() {
+        assert_eq!(escape_error(r"é∂\q"), (5, 2, "Invalid escape sequence."));
+        assert_eq!(escape_error(r"a\é"), (1, 3, "Invalid escape sequence."));
+        assert_eq!(escape_error(r"∂\u{D800}"), (3, 8, "Invalid unicode code point."));
+    }
+
+    #[test]
+    fn whitespace_runs_are_skipped() {
+        assert_eq!(types("  \t\r\n  a  \t  b\t\t"), vec![Identifier, Identifier, EOF]);
+        assert_eq!(types("a  // comment\nb"), vec![Identifier, Identifier, EOF]);
+        assert_eq!(types("a \t/* comment */  b"), vec![Identifier, Identifier, EOF]);
+    }
+
+    #[test]
+    fn line_comments() {
+        assert_eq!(types("// only a comment"), vec![EOF]);
+        assert_eq!(types("a // comment\n// another\nb"), vec![Identifier, Identifier, EOF]);
+        assert_eq!(types("a / b"), vec![Identifier, Slash, Identifier, EOF]);
+    }
+
+    #[test]
+    fn block_comments() {
+        assert_eq!(types("/* a */"), vec![EOF]);
+        assert_eq!(types("a /* b /* nested */ c */ d"), vec![Identifier, Identifier, EOF]);
+        assert_eq!(types("/**/a/***/"), vec![Identifier, EOF]);
+        assert_eq!(single_error("/* open"), ScanError::UnterminatedComment);
+        assert_eq!(single_error("/* outer /* inner */"), ScanError::UnterminatedComment);
+    }
+
+    #[test]
+    fn line_counting() {
+        let source = "a\nb\n\n  c // x\n/* one\ntwo */ d \"multi\nline\" e";
+        let lines: Vec<u32> = scan_all(source).iter().map(|token| token.line).collect();
+        assert_eq!(lines, vec![1, 2, 4, 6, 6, 7, 7]);
+    }
+
+    #[test]
+    fn columns_count_characters() {
+        let columns: Vec<u32> = scan_all("a  bc\n\té ∂ x").iter().map(|token| token.column).collect();
+        assert_eq!(columns, vec![1, 4, 2, 4, 6, 7]);
+        assert_eq!(single_error("é ∂"), ScanError::UnexpectedCharacter('∂'));
+    }
+
+    #[test]
+    fn end_of_file() {
+        assert_eq!(types(""), vec![EOF]);
+        assert_eq!(types("   \n\t"), vec![EOF]);
+
+        // Lookahead at the last byte must not run off the end.
+        assert_eq!(types("/"), vec![Slash, EOF]);
+        assert_eq!(types("a /"), vec![Identifier, Slash, EOF]);
+        assert_eq!(types("1."), vec![Number, Dot, EOF]);
+        assert_eq!(types("!"), vec![Bang, EOF]);
+
+        // Scanning past the end keeps returning EOF.
+        let mut scanner = Scanner::new("a");
+        scanner.scan();
+        for _ in 0..3 {
+            assert_eq!(scanner.scan().token_type, EOF);
+        }
+    }
 }
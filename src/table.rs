@@ -0,0 +1,282 @@
+use crate::memory::*;
+use crate::object::*;
+use crate::value::*;
+
+const TABLE_MAX_LOAD: f64 = 0.75;
+
+// Open-addressed hash table keyed by interned strings. Keys compare by
+// identity, so it only works for strings that went through the intern table.
+pub struct Table {
+    count: usize, // live entries plus tombstones
+    entries: Vec<Entry>,
+}
+
+#[derive(Copy, Clone)]
+struct Entry {
+    key: Option<Ref<LoxString>>,
+    value: Value, // Nil for an empty bucket, true for a tombstone
+}
+
+impl Table {
+    pub fn new() -> Table {
+        Table {
+            count: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn get(&self, key: Ref<LoxString>) -> Option<Value> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let entry = &self.entries[find_entry(&self.entries, key)];
+        entry.key.map(|_| entry.value)
+    }
+
+    /// Returns true if `key` was not already in the table.
+    pub fn set(&mut self, key: Ref<LoxString>, value: Value) -> bool {
+        if (self.count + 1) as f64 > self.entries.len() as f64 * TABLE_MAX_LOAD {
+            let capacity = if self.entries.len() < 8 { 8 } else { self.entries.len() * 2 };
+            self.adjust_capacity(capacity);
+        }
+
+        let index = find_entry(&self.entries, key);
+        let entry = &mut self.entries[index];
+        let is_new_key = entry.key.is_none();
+        if is_new_key && entry.value == Value::Nil {
+            self.count += 1;
+        }
+
+        entry.key = Some(key);
+        entry.value = value;
+        return is_new_key;
+    }
+
+    pub fn find_string(&self, chars: &str, hash: u32) -> Option<Ref<LoxString>> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let mask = self.entries.len() - 1;
+        let mut index = hash as usize & mask;
+        loop {
+            let entry = &self.entries[index];
+            match entry.key {
+                None => {
+                    // Stop at an empty bucket, skip over tombstones.
+                    if entry.value == Value::Nil {
+                        return None;
+                    }
+                }
+                Some(key) => {
+                    let key_str = key.obj();
+                    if key_str.hash == hash && key_str.chars == chars {
+                        return Some(key);
+                    }
+                }
+            }
+
+            index = (index + 1) & mask;
+        }
+    }
+
+    /// Deletes every entry whose key fails `keep`.
+    pub fn retain_keys<F: Fn(Ref<LoxString>) -> bool>(&mut self, keep: F) {
+        for entry in self.entries.iter_mut() {
+            if let Some(key) = entry.key {
+                if !keep(key) {
+                    entry.key = None;
+                    entry.value = Value::Bool(true);
+                }
+            }
+        }
+    }
+
+    pub fn add_all(&self, to: &mut Table) {
+        for (key, value) in self.iter() {
+            to.set(key, value);
+        }
+    }
+
+    pub fn allocated_bytes(&self) -> usize {
+        self.entries.capacity() * std::mem::size_of::<Entry>()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Ref<LoxString>, Value)> + '_ {
+        self.entries
+            .iter()
+            .filter_map(|entry| entry.key.map(|key| (key, entry.value)))
+    }
+
+    fn adjust_capacity(&mut self, capacity: usize) {
+        let empty = Entry {
+            key: None,
+            value: Value::Nil,
+        };
+        let old_entries = std::mem::replace(&mut self.entries, vec![empty; capacity]);
+
+        // Tombstones are dropped on the way over.
+        self.count = 0;
+        for entry in old_entries {
+            if let Some(key) = entry.key {
+                let index = find_entry(&self.entries, key);
+                self.entries[index] = entry;
+                self.count += 1;
+            }
+        }
+    }
+}
+
+fn find_entry(entries: &[Entry], key: Ref<LoxString>) -> usize {
+    let mask = entries.len() - 1;
+    let mut index = key.obj().hash as usize & mask;
+    let mut tombstone = None;
+
+    loop {
+        let entry = &entries[index];
+        match entry.key {
+            None => {
+                if entry.value == Value::Nil {
+                    // Reuse the first tombstone we passed, if any.
+                    return tombstone.unwrap_or(index);
+                } else if tombstone.is_none() {
+                    tombstone = Some(index);
+                }
+            }
+            Some(existing) if existing.ptr_eq(&key) => return index,
+            Some(_) => {}
+        }
+
+        index = (index + 1) & mask;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Keys with a chosen hash, so tests can make them collide. They skip the
+    // intern table, which is fine as long as each one is only made once.
+    fn key(heap: &mut Heap, chars: &str, hash: u32) -> Ref<LoxString> {
+        heap.manage(LoxString {
+            chars: chars.to_string(),
+            hash,
+        })
+    }
+
+    fn tombstones(table: &Table) -> usize {
+        table.entries.iter().filter(|entry| entry.key.is_none() && entry.value != Value::Nil).count()
+    }
+
+    #[test]
+    fn set_and_get() {
+        let mut heap = Heap::new();
+        let a = key(&mut heap, "a", 1);
+        let b = key(&mut heap, "b", 2);
+
+        let mut table = Table::new();
+        assert!(table.get(a).is_none());
+        assert!(table.set(a, Value::Number(1.0)));
+        assert!(!table.set(a, Value::Number(2.0)));
+        assert!(table.get(a) == Some(Value::Number(2.0)));
+        assert!(table.get(b).is_none());
+    }
+
+    #[test]
+    fn colliding_keys_probe_past_each_other() {
+        let mut heap = Heap::new();
+        let keys: Vec<_> = ["a", "b", "c"].iter().map(|chars| key(&mut heap, chars, 7)).collect();
+
+        let mut table = Table::new();
+        for (i, &key) in keys.iter().enumerate() {
+            table.set(key, Value::Number(i as f64));
+        }
+        for (i, &key) in keys.iter().enumerate() {
+            assert!(table.get(key) == Some(Value::Number(i as f64)));
+        }
+        assert_eq!(table.find_string("c", 7).map(|key| key.ptr_eq(&keys[2])), Some(true));
+    }
+
+    #[test]
+    fn lookups_probe_past_tombstones() {
+        let mut heap = Heap::new();
+        let a = key(&mut heap, "a", 7);
+        let b = key(&mut heap, "b", 7);
+        let c = key(&mut heap, "c", 7);
+
+        let mut table = Table::new();
+        table.set(a, Value::Nil);
+        table.set(b, Value::Nil);
+        table.set(c, Value::Nil);
+        table.retain_keys(|key| !key.ptr_eq(&b));
+
+        assert_eq!(tombstones(&table), 1);
+        assert!(table.get(b).is_none());
+        assert!(table.get(c).is_some());
+        assert!(table.find_string("b", 7).is_none());
+        assert_eq!(table.find_string("c", 7).map(|key| key.ptr_eq(&c)), Some(true));
+    }
+
+    #[test]
+    fn new_keys_reuse_tombstones() {
+        let mut heap = Heap::new();
+        let a = key(&mut heap, "a", 7);
+        let b = key(&mut heap, "b", 7);
+        let c = key(&mut heap, "c", 7);
+        let d = key(&mut heap, "d", 7);
+
+        let mut table = Table::new();
+        table.set(a, Value::Nil);
+        table.set(b, Value::Nil);
+        table.set(c, Value::Nil);
+        let slot = find_entry(&table.entries, b);
+        table.retain_keys(|key| !key.ptr_eq(&b));
+
+        // The tombstone already counts towards the load, so taking it over
+        // doesn't count again.
+        let count = table.count;
+        assert!(table.set(d, Value::Nil));
+        assert_eq!(find_entry(&table.entries, d), slot);
+        assert_eq!(table.count, count);
+        assert_eq!(tombstones(&table), 0);
+        assert!(table.get(c).is_some());
+    }
+
+    #[test]
+    fn growing_drops_tombstones() {
+        let mut heap = Heap::new();
+        let keys: Vec<_> = (0..6).map(|i| key(&mut heap, &format!("k{}", i), i)).collect();
+
+        let mut table = Table::new();
+        for &key in keys.iter() {
+            table.set(key, Value::Nil);
+        }
+        table.retain_keys(|key| key.obj().hash % 2 == 0);
+        assert_eq!(tombstones(&table), 3);
+        assert_eq!(table.entries.len(), 8);
+
+        // Six buckets used out of eight is the load limit, so this grows.
+        let extra = key(&mut heap, "extra", 100);
+        table.set(extra, Value::Nil);
+        assert_eq!(table.entries.len(), 16);
+        assert_eq!(tombstones(&table), 0);
+        assert_eq!(table.count, 4);
+        for &key in keys.iter() {
+            assert_eq!(table.get(key).is_some(), key.obj().hash % 2 == 0);
+        }
+    }
+
+    #[test]
+    fn interned_strings_are_held_weakly() {
+        let mut heap = Heap::new();
+        let kept = heap.manage_str("kept".to_string());
+        heap.manage_str("dropped".to_string());
+
+        heap.mark_object(Obj::LString(kept));
+        heap.collect_garbage();
+
+        assert_eq!(heap.find_string("kept").map(|key| key.ptr_eq(&kept)), Some(true));
+        assert!(heap.find_string("dropped").is_none());
+    }
+}
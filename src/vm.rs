@@ -1,45 +1,190 @@
 use crate::chunk::*;
 use crate::compiler::*;
+use crate::diagnostic::*;
+use crate::handle::*;
 use crate::memory::*;
 use crate::object::*;
 use crate::value::*;
 use crate::value::Value::*;
 use num_traits::FromPrimitive;
+use crate::table::*;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-const STACK_MAX: usize = 256;
+const FRAMES_MAX: usize = 64;
+const STACK_MAX: usize = FRAMES_MAX * (u8::MAX as usize + 1);
 
 pub struct VM {
-    pub chunk: Chunk,
-    pub ip: usize,
-    pub stack: Vec<Value>,
-    pub heap: Heap,
+    frames: Vec<CallFrame>,
+    stack: Vec<Value>,
+    pub(crate) heap: Heap,
+    globals: HashMap<Ref<LoxString>, Value>,
+    open_upvalues: Vec<Ref<Upvalue>>, // sorted by stack slot, innermost last
+    init_string: Ref<LoxString>,
+    reporter: Box<dyn Reporter>,
+    roots: Rc<RefCell<Roots>>, // objects Rust holds handles to
+}
+
+impl Default for VM {
+    fn default() -> VM {
+        VM::new()
+    }
+}
+
+impl Drop for VM {
+    fn drop(&mut self) {
+        // Handles can outlive the VM, but the objects they point to can't.
+        self.roots.borrow_mut().heap_dropped();
+    }
+}
+
+pub struct CallFrame {
+    closure: Ref<Closure>,
+    ip: usize,
+    slot_base: usize,
 }
 
 impl VM {
     pub fn new() -> VM {
-        VM {
-            chunk: Chunk::new(),
-            ip: 0,
-            stack: vec![Nil; STACK_MAX],
-            heap: Heap::new(),
+        let mut heap = Heap::new();
+        let init_string = heap.manage_str("init".to_string());
+
+        let mut vm = VM {
+            frames: Vec::with_capacity(FRAMES_MAX),
+            stack: Vec::with_capacity(STACK_MAX),
+            heap: heap,
+            globals: HashMap::new(),
+            open_upvalues: Vec::new(),
+            init_string: init_string,
+            reporter: Box::new(StderrReporter),
+            roots: Roots::new(),
+        };
+
+        vm.define_native("clock", 0, clock_native);
+        vm
+    }
+
+    /// Binds a Rust function to the global `name`. The VM checks the
+    /// argument count against `arity` before calling it, and an `Err`
+    /// returned from it is reported as a runtime error.
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        let name = self.heap.manage_str(name.to_string());
+        let native = self.heap.manage(Native { arity, function });
+        self.globals.insert(name, Object(Obj::Native(native)));
+    }
+
+    /// Compiles and runs `source` as a script. Globals defined by earlier
+    /// calls stay visible, which is what the REPL relies on. A native may
+    /// call this too; a runtime error then only unwinds the nested script.
+    pub fn interpret(&mut self, source: &str) -> InterpretResult {
+        let stack_len = self.stack.len();
+        let depth = self.frames.len();
+        let compiler = Compiler::new(source, self);
+        let function = match compiler.compile() {
+            Ok(function) => function,
+            Err(diagnostics) => {
+                for diagnostic in diagnostics.iter() {
+                    self.reporter.report(diagnostic);
+                }
+                return InterpretResult::CompileError(diagnostics);
+            }
+        };
+
+        self.push(Object(Obj::Function(function)));
+        let closure = self.heap.manage(Closure {
+            function,
+            upvalues: Vec::new(),
+        });
+        self.pop();
+        self.push(Object(Obj::Closure(closure)));
+        let result = self.call(closure, 0).and_then(|_| self.run());
+        match result {
+            Ok(()) => {
+                self.pop(); // The script's implicit nil.
+                InterpretResult::Ok
+            }
+            Err(diagnostic) => {
+                self.unwind(stack_len, depth);
+                InterpretResult::RuntimeError(diagnostic)
+            }
         }
     }
 
-    pub fn interpret(&mut self, source: &String) -> InterpretResult {
-        let mut chunk = Chunk::new();
-        let mut compiler = Compiler::new(source, &mut chunk);
+    /// Replaces the reporter that diagnostics are sent to as they occur.
+    /// They are returned from `interpret` as well either way.
+    pub fn set_reporter(&mut self, reporter: Box<dyn Reporter>) {
+        self.reporter = reporter;
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Handle> {
+        let name = self.heap.find_string(name)?;
+        let value = self.globals.get(&name)?;
+        Some(self.handle(*value))
+    }
 
-        if !compiler.compile() {
-            return InterpretResult::CompileError;
+    /// Panics if `value` holds an object from a different VM.
+    pub fn set_global(&mut self, name: &str, value: impl Into<Handle>) {
+        let value = value.into().value_for(&self.roots);
+        let name = self.heap.manage_str(name.to_string());
+        self.globals.insert(name, value);
+    }
+
+    /// Allocates a Lox string, kept alive for as long as the handle is.
+    pub fn new_string(&mut self, chars: &str) -> Handle {
+        let string = self.heap.manage_str(chars.to_string());
+        self.handle(Object(Obj::LString(string)))
+    }
+
+    /// Calls the global function, class or native `name` with `args` and
+    /// returns its result. On a runtime error only this call is unwound, so
+    /// a native can call back into Lox and carry on if that fails.
+    pub fn call_function(&mut self, name: &str, args: &[Handle]) -> Result<Handle, Diagnostic> {
+        let callee = match self.get_global(name) {
+            Some(callee) => callee.value_for(&self.roots),
+            None => return Err(crate::error!(self, "Undefined variable '{}'.", name)),
+        };
+
+        let stack_len = self.stack.len();
+        let depth = self.frames.len();
+        self.push(callee);
+        for arg in args {
+            let arg = arg.value_for(&self.roots);
+            self.push(arg);
+        }
+
+        let result = self.call_value(callee, args.len()).and_then(|_| {
+            // Natives have already left their result on the stack.
+            if self.frames.len() > depth {
+                self.run()?;
+            }
+            Ok(())
+        });
+
+        match result {
+            Ok(()) => {
+                let value = self.pop();
+                Ok(self.handle(value))
+            }
+            Err(diagnostic) => {
+                self.unwind(stack_len, depth);
+                Err(diagnostic)
+            }
         }
+    }
 
-        self.chunk = chunk;
-        self.ip = 0;
-        self.run()
+    fn handle(&self, value: Value) -> Handle {
+        Handle::new(value, &self.roots)
     }
 
-    fn reset_stack(&mut self) {
-        self.stack.clear();
+    // Drops what a failed call or script left above the given stack length
+    // and frame depth. Closures that escaped must keep their values once
+    // those slots are gone, so their upvalues are closed first.
+    fn unwind(&mut self, stack_len: usize, depth: usize) {
+        self.close_upvalues(stack_len);
+        self.stack.truncate(stack_len);
+        self.frames.truncate(depth);
     }
 
     fn push(&mut self, value: Value) {
@@ -50,13 +195,266 @@ impl VM {
         self.stack.pop().unwrap()
     }
 
-    fn peek(&mut self, distance: usize) -> Value {
+    fn peek(&self, distance: usize) -> Value {
         let index = self.stack.len() - 1 - distance;
         return self.stack[index];
     }
 
-    pub fn run(&mut self) -> InterpretResult {
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frame_mut();
+        let byte = frame.closure.obj().function.obj().chunk.code[frame.ip];
+        frame.ip += 1;
+        byte
+    }
+
+    fn read_short(&mut self) -> u16 {
+        let high = self.read_byte() as u16;
+        let low = self.read_byte() as u16;
+        (high << 8) | low
+    }
+
+    // Long instructions carry a 24-bit constant index instead of one byte.
+    fn read_constant(&mut self, long: bool) -> Value {
+        let index = if long {
+            let high = self.read_byte() as usize;
+            (high << 16) | self.read_short() as usize
+        } else {
+            self.read_byte() as usize
+        };
+        self.frame().closure.obj().function.obj().chunk.constants[index]
+    }
+
+    fn read_string(&mut self, long: bool) -> Ref<LoxString> {
+        match self.read_constant(long) {
+            Object(Obj::LString(s)) => s,
+            _ => panic!("Expected a string constant."),
+        }
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), Diagnostic> {
+        match callee {
+            Object(Obj::BoundMethod(bound)) => {
+                let bound = bound.obj();
+                let index = self.stack.len() - arg_count - 1;
+                self.stack[index] = bound.receiver;
+                return self.call(bound.method, arg_count);
+            }
+            Object(Obj::Closure(closure)) => return self.call(closure, arg_count),
+            Object(Obj::Native(native)) => return self.call_native(native, arg_count),
+            Object(Obj::Class(class)) => {
+                let instance = self.heap.manage(Instance {
+                    class,
+                    fields: RefCell::new(Table::new()),
+                });
+                let index = self.stack.len() - arg_count - 1;
+                self.stack[index] = Object(Obj::Instance(instance));
+
+                let initializer = class.obj().methods.borrow().get(self.init_string);
+                match initializer {
+                    Some(Object(Obj::Closure(initializer))) => return self.call(initializer, arg_count),
+                    _ if arg_count != 0 => {
+                        return Err(crate::error!(self, "Expected 0 arguments but got {}.", arg_count));
+                    }
+                    _ => return Ok(()),
+                }
+            }
+            _ => {}
+        }
+
+        return Err(crate::error!(self, "Can only call functions and classes."));
+    }
+
+    fn call_native(&mut self, native: Ref<Native>, arg_count: usize) -> Result<(), Diagnostic> {
+        let native = native.obj();
+        if arg_count != native.arity {
+            return Err(crate::error!(self, "Expected {} arguments but got {}.", native.arity, arg_count));
+        }
+
+        let args_start = self.stack.len() - arg_count;
+        let args: Vec<Handle> = self.stack[args_start..].iter().map(|&arg| self.handle(arg)).collect();
+        match (native.function)(self, &args) {
+            Ok(result) => {
+                let result = result.value_for(&self.roots);
+                // Drop the arguments and the native itself.
+                self.stack.truncate(args_start - 1);
+                self.push(result);
+                return Ok(());
+            }
+            Err(message) => {
+                return Err(self.runtime_error(message));
+            }
+        }
+    }
+
+    fn invoke(&mut self, name: Ref<LoxString>, arg_count: usize) -> Result<(), Diagnostic> {
+        let instance = match self.peek(arg_count) {
+            Object(Obj::Instance(instance)) => instance,
+            _ => {
+                return Err(crate::error!(self, "Only instances have methods."));
+            }
+        };
+
+        // A field holding a callable shadows any method of the same name.
+        let field = instance.obj().fields.borrow().get(name);
+        if let Some(value) = field {
+            let index = self.stack.len() - arg_count - 1;
+            self.stack[index] = value;
+            return self.call_value(value, arg_count);
+        }
+
+        return self.invoke_from_class(instance.obj().class, name, arg_count);
+    }
+
+    fn invoke_from_class(&mut self, class: Ref<Class>, name: Ref<LoxString>, arg_count: usize) -> Result<(), Diagnostic> {
+        let method = class.obj().methods.borrow().get(name);
+        match method {
+            Some(Object(Obj::Closure(method))) => return self.call(method, arg_count),
+            _ => {
+                return Err(crate::error!(self, "Undefined property '{}'.", name.obj()));
+            }
+        }
+    }
+
+    fn bind_method(&mut self, class: Ref<Class>, name: Ref<LoxString>) -> Result<(), Diagnostic> {
+        let method = class.obj().methods.borrow().get(name);
+        let method = match method {
+            Some(Object(Obj::Closure(method))) => method,
+            _ => {
+                return Err(crate::error!(self, "Undefined property '{}'.", name.obj()));
+            }
+        };
+
+        let bound = self.heap.manage(BoundMethod {
+            receiver: self.peek(0),
+            method,
+        });
+        self.pop();
+        self.push(Object(Obj::BoundMethod(bound)));
+        return Ok(());
+    }
+
+    fn define_method(&mut self, name: Ref<LoxString>) {
+        let method = self.peek(0);
+        if let Object(Obj::Class(class)) = self.peek(1) {
+            self.heap.grow_table(&mut class.obj().methods.borrow_mut(), |methods| methods.set(name, method));
+        }
+        self.pop();
+    }
+
+    fn call(&mut self, closure: Ref<Closure>, arg_count: usize) -> Result<(), Diagnostic> {
+        let function = closure.obj().function;
+        if arg_count != function.obj().arity {
+            return Err(crate::error!(self, "Expected {} arguments but got {}.", function.obj().arity, arg_count));
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            return Err(crate::error!(self, "Stack overflow."));
+        }
+
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slot_base: self.stack.len() - arg_count - 1,
+        });
+        return Ok(());
+    }
+
+    fn capture_upvalue(&mut self, location: usize) -> Ref<Upvalue> {
+        let position = self
+            .open_upvalues
+            .iter()
+            .rposition(|upvalue| upvalue.obj().location <= location);
+
+        if let Some(index) = position {
+            let upvalue = self.open_upvalues[index];
+            if upvalue.obj().location == location {
+                return upvalue;
+            }
+        }
+
+        let created = self.heap.manage(Upvalue {
+            location,
+            closed: Cell::new(None),
+        });
+        let insert_at = position.map_or(0, |index| index + 1);
+        self.open_upvalues.insert(insert_at, created);
+        return created;
+    }
+
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let upvalue = upvalue.obj();
+            if upvalue.location < last {
+                break;
+            }
+
+            upvalue.closed.set(Some(self.stack[upvalue.location]));
+            self.open_upvalues.pop();
+        }
+    }
+
+    fn read_upvalue(&self, upvalue: Ref<Upvalue>) -> Value {
+        let upvalue = upvalue.obj();
+        match upvalue.closed.get() {
+            Some(value) => value,
+            None => self.stack[upvalue.location],
+        }
+    }
+
+    fn write_upvalue(&mut self, upvalue: Ref<Upvalue>, value: Value) {
+        let upvalue = upvalue.obj();
+        match upvalue.closed.get() {
+            Some(_) => upvalue.closed.set(Some(value)),
+            None => self.stack[upvalue.location] = value,
+        }
+    }
+
+    pub(crate) fn collect_garbage(&mut self) {
+        for &value in self.stack.iter() {
+            self.heap.mark_value(value);
+        }
+
+        for frame in self.frames.iter() {
+            self.heap.mark_object(Obj::Closure(frame.closure));
+        }
+
+        for &upvalue in self.open_upvalues.iter() {
+            self.heap.mark_object(Obj::Upvalue(upvalue));
+        }
+
+        for (&name, &value) in self.globals.iter() {
+            self.heap.mark_object(Obj::LString(name));
+            self.heap.mark_value(value);
+        }
+
+        for value in self.roots.borrow().values() {
+            self.heap.mark_value(value);
+        }
+
+        self.heap.mark_object(Obj::LString(self.init_string));
+        self.heap.collect_garbage();
+    }
+
+    // Runs until the frame on top when called returns, leaving its result on
+    // the stack.
+    fn run(&mut self) -> Result<(), Diagnostic> {
+        let base_depth = self.frames.len() - 1;
+
         loop {
+            // Between instructions every live object is reachable from the
+            // stack, frames, open upvalues or globals.
+            if self.heap.should_collect() {
+                self.collect_garbage();
+            }
+
             if cfg!(feature = "DEBUG_TRACE_EXECUTION") {
                 print!("          ");
                 for value in &self.stack {
@@ -64,31 +462,118 @@ impl VM {
                     value.print();
                     print!(" ]");
                 }
-                println!("");
+                println!();
 
-                self.chunk.disassemble_instruction(self.ip);
+                let frame = self.frame();
+                frame.closure.obj().function.obj().chunk.disassemble_instruction(frame.ip);
             }
 
-            let instruction = FromPrimitive::from_u8(self.chunk.code[self.ip]).unwrap(); // TODO:
-            self.ip += 1;
+            let instruction = FromPrimitive::from_u8(self.read_byte()).unwrap(); // TODO:
 
             match instruction {
-                Op::Constant => {
-                    let byte = self.chunk.code[self.ip] as usize;
-                    self.ip += 1;
-                    let constant = &self.chunk.constants[byte];
-                    let value = match constant {
-                        Constant::Number(n) => Number(*n),
-                        Constant::String(s) => {
-                            let s = s.clone();
-                            Object(Obj::LString(self.heap.manage_str(s)))
-                        }
-                    };
-                    self.push(value);
+                Op::Constant | Op::ConstantLong => {
+                    let constant = self.read_constant(instruction.is_long());
+                    self.push(constant);
                 }
                 Op::Nil => self.push(Nil),
                 Op::False => self.push(Bool(false)),
                 Op::True => self.push(Bool(true)),
+                Op::Pop => {
+                    self.pop();
+                }
+                Op::Dup => self.push(self.peek(0)),
+                Op::Over => self.push(self.peek(1)),
+                Op::Swap => {
+                    let top = self.stack.len() - 1;
+                    self.stack.swap(top, top - 1);
+                }
+                Op::GetLocal => {
+                    let slot = self.read_byte() as usize;
+                    let value = self.stack[self.frame().slot_base + slot];
+                    self.push(value);
+                }
+                Op::SetLocal => {
+                    let slot = self.read_byte() as usize;
+                    let slot_base = self.frame().slot_base;
+                    self.stack[slot_base + slot] = self.peek(0);
+                }
+                Op::GetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.frame().closure.obj().upvalues[slot];
+                    let value = self.read_upvalue(upvalue);
+                    self.push(value);
+                }
+                Op::SetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.frame().closure.obj().upvalues[slot];
+                    self.write_upvalue(upvalue, self.peek(0));
+                }
+                Op::GetGlobal | Op::GetGlobalLong => {
+                    let name = self.read_string(instruction.is_long());
+                    match self.globals.get(&name) {
+                        Some(&value) => self.push(value),
+                        None => {
+                            return Err(crate::error!(self, "Undefined variable '{}'.", name.obj()));
+                        }
+                    }
+                }
+                Op::DefineGlobal | Op::DefineGlobalLong => {
+                    let name = self.read_string(instruction.is_long());
+                    let value = self.peek(0);
+                    self.globals.insert(name, value);
+                    self.pop();
+                }
+                Op::SetGlobal | Op::SetGlobalLong => {
+                    let name = self.read_string(instruction.is_long());
+                    let value = self.peek(0);
+                    match self.globals.get_mut(&name) {
+                        Some(global) => *global = value,
+                        None => {
+                            return Err(crate::error!(self, "Undefined variable '{}'.", name.obj()));
+                        }
+                    }
+                }
+                Op::GetProperty | Op::GetPropertyLong => {
+                    let instance = match self.peek(0) {
+                        Object(Obj::Instance(instance)) => instance,
+                        _ => {
+                            return Err(crate::error!(self, "Only instances have properties."));
+                        }
+                    };
+
+                    let name = self.read_string(instruction.is_long());
+                    let value = instance.obj().fields.borrow().get(name);
+                    if let Some(value) = value {
+                        self.pop(); // Instance.
+                        self.push(value);
+                    } else {
+                        self.bind_method(instance.obj().class, name)?;
+                    }
+                }
+                Op::SetProperty | Op::SetPropertyLong => {
+                    let instance = match self.peek(1) {
+                        Object(Obj::Instance(instance)) => instance,
+                        _ => {
+                            return Err(crate::error!(self, "Only instances have fields."));
+                        }
+                    };
+
+                    let name = self.read_string(instruction.is_long());
+                    let value = self.peek(0);
+                    self.heap.grow_table(&mut instance.obj().fields.borrow_mut(), |fields| fields.set(name, value));
+                    let value = self.pop();
+                    self.pop(); // Instance.
+                    self.push(value);
+                }
+                Op::GetSuper | Op::GetSuperLong => {
+                    let name = self.read_string(instruction.is_long());
+                    let superclass = match self.pop() {
+                        Object(Obj::Class(superclass)) => superclass,
+                        _ => panic!("Expected a superclass."),
+                    };
+
+                    self.bind_method(superclass, name)?;
+                }
                 Op::Equal => {
                     let b = self.pop();
                     let a = self.pop();
@@ -107,14 +592,14 @@ impl VM {
                             self.push(Object(Obj::LString(r)));
                         }
                         _ => {
-                            crate::error!(self, "Operands must be numbers or strings.");
-                            return InterpretResult::RuntimeError;
+                            return Err(crate::error!(self, "Operands must be numbers or strings."));
                         }
                     }
                 }
                 Op::Subtract => crate::binary_op!(self, Number, -),
                 Op::Multiply => crate::binary_op!(self, Number, *),
                 Op::Divide => crate::binary_op!(self, Number, /),
+                Op::Modulo => crate::binary_op!(self, Number, %),
                 Op::Not => {
                     let val = self.pop();
                     self.push(Bool(is_falsey(val)))
@@ -123,31 +608,163 @@ impl VM {
                     match self.pop() {
                         Number(n) => self.push(Number(-n)),
                         _ => {
-                            crate::error!(self, "Operand must be a number.");
-                            return InterpretResult::RuntimeError;
+                            return Err(crate::error!(self, "Operand must be a number."));
                         }
                     }
                 }
-                Op::Return => {
+                Op::Print => {
                     self.pop().print();
-                    println!("");
-                    return InterpretResult::Ok;
+                    println!();
+                }
+                Op::Jump => {
+                    let offset = self.read_short() as usize;
+                    self.frame_mut().ip += offset;
+                }
+                Op::JumpIfFalse => {
+                    let offset = self.read_short() as usize;
+                    if is_falsey(self.peek(0)) {
+                        self.frame_mut().ip += offset;
+                    }
+                }
+                Op::Loop => {
+                    let offset = self.read_short() as usize;
+                    self.frame_mut().ip -= offset;
+                }
+                Op::Call => {
+                    let arg_count = self.read_byte() as usize;
+                    self.call_value(self.peek(arg_count), arg_count)?;
+                }
+                Op::Invoke | Op::InvokeLong => {
+                    let method = self.read_string(instruction.is_long());
+                    let arg_count = self.read_byte() as usize;
+                    self.invoke(method, arg_count)?;
+                }
+                Op::SuperInvoke | Op::SuperInvokeLong => {
+                    let method = self.read_string(instruction.is_long());
+                    let arg_count = self.read_byte() as usize;
+                    let superclass = match self.pop() {
+                        Object(Obj::Class(superclass)) => superclass,
+                        _ => panic!("Expected a superclass."),
+                    };
+
+                    self.invoke_from_class(superclass, method, arg_count)?;
+                }
+                Op::Closure | Op::ClosureLong => {
+                    let function = match self.read_constant(instruction.is_long()) {
+                        Object(Obj::Function(function)) => function,
+                        _ => panic!("Expected a function constant."),
+                    };
+
+                    let upvalue_count = function.obj().upvalue_count;
+                    let mut upvalues = Vec::with_capacity(upvalue_count);
+                    for _ in 0..upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        if is_local {
+                            let location = self.frame().slot_base + index;
+                            upvalues.push(self.capture_upvalue(location));
+                        } else {
+                            upvalues.push(self.frame().closure.obj().upvalues[index]);
+                        }
+                    }
+
+                    let closure = self.heap.manage(Closure { function, upvalues });
+                    self.push(Object(Obj::Closure(closure)));
+                }
+                Op::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                Op::Return => {
+                    let result = self.pop();
+                    let slot_base = self.frame().slot_base;
+                    self.close_upvalues(slot_base);
+
+                    let frame = self.frames.pop().unwrap();
+                    self.stack.truncate(frame.slot_base);
+                    self.push(result);
+
+                    if self.frames.len() == base_depth {
+                        return Ok(());
+                    }
+                }
+                Op::Class | Op::ClassLong => {
+                    let name = self.read_string(instruction.is_long());
+                    let class = self.heap.manage(Class {
+                        name,
+                        methods: RefCell::new(Table::new()),
+                    });
+                    self.push(Object(Obj::Class(class)));
+                }
+                Op::Inherit => {
+                    let superclass = match self.peek(1) {
+                        Object(Obj::Class(superclass)) => superclass,
+                        _ => {
+                            return Err(crate::error!(self, "Superclass must be a class."));
+                        }
+                    };
+
+                    if let Object(Obj::Class(subclass)) = self.peek(0) {
+                        // Copy-down inheritance: methods defined in the subclass
+                        // body are added afterwards and override these.
+                        let methods = superclass.obj().methods.borrow();
+                        self.heap
+                            .grow_table(&mut subclass.obj().methods.borrow_mut(), |inherited| methods.add_all(inherited));
+                    }
+                    self.pop(); // Subclass.
+                }
+                Op::Method | Op::MethodLong => {
+                    let name = self.read_string(instruction.is_long());
+                    self.define_method(name);
                 }
             };
         }
     }
 
-    fn runtime_error(&mut self, message: String) {
-        eprintln!("{}", message); // todo: format?
+    fn runtime_error(&mut self, message: String) -> Diagnostic {
+        let stack_trace: Vec<StackFrame> = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = frame.closure.obj().function.obj();
+                let instruction = frame.ip - 1;
+                StackFrame {
+                    function: function.name.map(|name| name.obj().chars.clone()),
+                    line: function.chunk.get_line(instruction) as usize,
+                }
+            })
+            .collect();
+
+        let mut diagnostic = Diagnostic {
+            severity: Severity::Error,
+            message,
+            line: 0,
+            column: None,
+            span: None,
+            source_line: None,
+            at: None,
+            stack_trace: Some(stack_trace),
+        };
 
-        let instruction = self.ip - 1;
-        let line = self.chunk.lines[instruction];
-        eprintln!("[line {}] in script", line);
-        self.reset_stack();
+        if let Some(frame) = self.frames.last() {
+            let chunk = &frame.closure.obj().function.obj().chunk;
+            let span = chunk.get_span(frame.ip - 1);
+            let start = span.start as usize;
+            diagnostic.line = span.line as usize;
+            diagnostic.column = Some(span.column as usize);
+            diagnostic.span = Some(start..start + span.length as usize);
+            diagnostic.source_line = Some(source_line(&chunk.source, start));
+        }
+        self.reporter.report(&diagnostic);
+        diagnostic
     }
+}
 
-    fn free_objects(&mut self) {
-        self.heap.clear();
+fn clock_native(_vm: &mut VM, _args: &[Handle]) -> Result<Handle, String> {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => Ok(Handle::from(elapsed.as_secs_f64())),
+        Err(_) => Err("System clock is set before the Unix epoch.".to_string()),
     }
 }
 
@@ -159,16 +776,18 @@ fn is_falsey(val: Value) -> bool{
     }
 }
 
+#[doc(hidden)]
 #[macro_export]
 macro_rules! error {
     ($vm: expr, $format: expr) => {
         $vm.runtime_error(format!($format))
     };
     ($vm: expr, $format: expr, $($args: expr),*) => {
-        $vm.runtime_error(format!($format, $($args)* ))
+        $vm.runtime_error(format!($format, $($args),* ))
     };
 }
 
+#[doc(hidden)]
 #[macro_export]
 macro_rules! binary_op {
     ($vm:expr, $value_ctor:tt, $op:tt) => {
@@ -178,16 +797,16 @@ macro_rules! binary_op {
                     $vm.push($value_ctor(a $op b));
                 },
                 _ => {
-                    crate::error!($vm, "Operands must be numbers.");
-                    return InterpretResult::RuntimeError;
+                    return Err($crate::error!($vm, "Operands must be numbers."));
                 }
             }
         }
     }
 }
 
+#[derive(Clone, PartialEq, Debug)]
 pub enum InterpretResult {
     Ok,
-    CompileError,
-    RuntimeError,
+    CompileError(Vec<Diagnostic>),
+    RuntimeError(Diagnostic),
 }
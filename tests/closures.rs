@@ -0,0 +1,85 @@
+mod common;
+
+use common::{number, run, runtime_error, vm};
+use rlox::VM;
+use std::convert::TryFrom;
+
+fn call(vm: &mut VM, name: &str) -> f64 {
+    f64::try_from(vm.call_function(name, &[]).unwrap()).unwrap()
+}
+
+#[test]
+fn closures_in_a_for_loop_capture_their_own_iteration() {
+    let mut vm = vm();
+    run(
+        &mut vm,
+        "\
+var first;
+var second;
+var third;
+for (var i = 0; i < 3; i++) {
+ fun get() { return i; }
+ if (i == 0) first = get;
+ if (i == 1) second = get;
+ if (i == 2) third = get;
+}
+",
+    );
+
+    assert_eq!(call(&mut vm, "first"), 0.0);
+    assert_eq!(call(&mut vm, "second"), 1.0);
+    assert_eq!(call(&mut vm, "third"), 2.0);
+}
+
+#[test]
+fn changes_to_the_loop_variable_in_the_body_carry_over() {
+    let mut vm = vm();
+    run(
+        &mut vm,
+        "\
+var count = 0;
+for (var i = 0; i < 10; i++) {
+ i += 2;
+ count++;
+}
+
+var set;
+var last;
+for (var j = 0; j < 10; j++) {
+ fun skip() { j = 10; }
+ set = skip;
+ last = j;
+ set();
+}
+",
+    );
+
+    assert_eq!(number(&vm, "count"), 4.0);
+    assert_eq!(number(&vm, "last"), 0.0);
+}
+
+#[test]
+fn closures_keep_their_values_after_a_runtime_error() {
+    let mut vm = vm();
+    run(&mut vm, "var deep; var shallow;");
+    runtime_error(
+        &mut vm,
+        "\
+{
+ var a = 1;
+ fun get_a() { return a; }
+ shallow = get_a;
+ var b = 2;
+ var c = 3;
+ var d = 4;
+ fun get_d() { return d; }
+ deep = get_d;
+ nil();
+}
+",
+    );
+
+    run(&mut vm, "var first = shallow(); var last = deep();");
+    assert_eq!(number(&vm, "first"), 1.0);
+    assert_eq!(number(&vm, "last"), 4.0);
+}
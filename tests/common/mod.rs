@@ -0,0 +1,37 @@
+// Helpers shared by the integration tests. Each test file is its own crate
+// and uses only some of them.
+#![allow(dead_code)]
+
+use rlox::{Diagnostic, InterpretResult, Reporter, VM};
+use std::convert::TryFrom;
+
+// Diagnostics are checked through `interpret`'s result instead.
+pub struct Silent;
+
+impl Reporter for Silent {
+    fn report(&mut self, _diagnostic: &Diagnostic) {}
+}
+
+pub fn vm() -> VM {
+    let mut vm = VM::new();
+    vm.set_reporter(Box::new(Silent));
+    vm
+}
+
+pub fn run(vm: &mut VM, source: &str) {
+    match vm.interpret(source) {
+        InterpretResult::Ok => {}
+        result => panic!("expected the script to run, got {:?}", result),
+    }
+}
+
+pub fn runtime_error(vm: &mut VM, source: &str) -> Diagnostic {
+    match vm.interpret(source) {
+        InterpretResult::RuntimeError(diagnostic) => diagnostic,
+        result => panic!("expected a runtime error, got {:?}", result),
+    }
+}
+
+pub fn number(vm: &VM, name: &str) -> f64 {
+    f64::try_from(vm.get_global(name).unwrap()).unwrap()
+}
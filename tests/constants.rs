@@ -0,0 +1,43 @@
+mod common;
+
+use common::{number, run, vm};
+
+// Scripts and functions with more than 256 constants need the 24-bit
+// operand forms of every constant-indexed instruction.
+#[test]
+fn long_constant_instructions_run_correctly() {
+    let mut source = String::new();
+    for i in 0..300 {
+        source.push_str(&format!("var g{} = {};\n", i, i));
+    }
+
+    // A method with 300 constants of its own, for the long super forms.
+    source.push_str("class Base { init(v) { this.value = v; } get() { return this.value; } }\n");
+    source.push_str("class Derived < Base {\n get() {\n var n = 0;\n");
+    for i in 0..300 {
+        source.push_str(&format!(" n = n + {}.5;\n", i));
+    }
+    source.push_str(" var method = super.get;\n return method() + super.get() + n;\n }\n}\n");
+
+    source.push_str(
+        "\
+g299 = g298 + g297;
+var big = 123456.25;
+var d = Derived(g2);
+d.extra = g299;
+var from_property = d.extra;
+var from_invoke = d.get();
+",
+    );
+
+    let mut vm = vm();
+    run(&mut vm, &source);
+
+    let n: f64 = (0..300).map(|i| i as f64 + 0.5).sum();
+    assert_eq!(number(&vm, "g0"), 0.0);
+    assert_eq!(number(&vm, "g298"), 298.0);
+    assert_eq!(number(&vm, "g299"), 595.0);
+    assert_eq!(number(&vm, "big"), 123456.25);
+    assert_eq!(number(&vm, "from_property"), 595.0);
+    assert_eq!(number(&vm, "from_invoke"), 2.0 + 2.0 + n);
+}
@@ -0,0 +1,135 @@
+mod common;
+
+use common::{number, run, vm};
+use rlox::{Handle, InterpretResult, VM};
+use std::convert::TryFrom;
+
+#[test]
+fn conversions_round_trip() {
+    let mut vm = vm();
+    assert_eq!(f64::try_from(Handle::from(1.5)), Ok(1.5));
+    assert_eq!(bool::try_from(Handle::from(true)), Ok(true));
+    assert!(Handle::from(()).is_nil());
+
+    let string = vm.new_string("héllo");
+    assert_eq!(String::try_from(&string), Ok("héllo".to_string()));
+
+    assert!(f64::try_from(&string).is_err());
+    assert!(bool::try_from(Handle::from(1.0)).is_err());
+    assert!(String::try_from(Handle::from(())).is_err());
+}
+
+#[test]
+fn globals_are_shared_with_scripts() {
+    let mut vm = vm();
+    vm.set_global("x", 2.0);
+    let greeting = vm.new_string("hi");
+    vm.set_global("greeting", &greeting);
+    run(&mut vm, "var y = x * 3; var message = greeting + \"!\";");
+
+    assert_eq!(number(&vm, "y"), 6.0);
+    let message = vm.get_global("message").unwrap();
+    assert_eq!(String::try_from(message), Ok("hi!".to_string()));
+    assert!(vm.get_global("missing").is_none());
+}
+
+#[test]
+fn call_function_calls_lox_functions_and_natives() {
+    let mut vm = vm();
+    run(&mut vm, "fun add(a, b) { return a + b; }");
+
+    let sum = vm.call_function("add", &[Handle::from(1.0), Handle::from(2.0)]).unwrap();
+    assert_eq!(f64::try_from(sum), Ok(3.0));
+
+    let a = vm.new_string("a");
+    let b = vm.new_string("b");
+    let joined = vm.call_function("add", &[a, b]).unwrap();
+    assert_eq!(String::try_from(joined), Ok("ab".to_string()));
+
+    assert!(vm.call_function("clock", &[]).is_ok());
+    assert!(vm.call_function("missing", &[]).is_err());
+    assert!(vm.call_function("add", &[Handle::from(1.0)]).is_err());
+}
+
+fn recover(vm: &mut VM, _args: &[Handle]) -> Result<Handle, String> {
+    assert!(vm.call_function("missing", &[]).is_err());
+    assert!(vm.call_function("fail", &[]).is_err());
+    Ok(Handle::from(1.0))
+}
+
+#[test]
+fn errors_inside_call_function_only_unwind_that_call() {
+    let mut vm = vm();
+    vm.define_native("recover", 0, recover);
+    run(
+        &mut vm,
+        "\
+fun fail() { var a = 1; return a + nil; }
+fun outer() {
+ var before = 10;
+ var got = recover();
+ return before + got;
+}
+var result = outer();
+",
+    );
+    assert_eq!(number(&vm, "result"), 11.0);
+}
+
+#[test]
+fn handles_keep_objects_alive_across_collections() {
+    let mut vm = vm();
+    let string = vm.new_string("kept by Rust only");
+    run(&mut vm, "fun make() { return \"fresh\" + \"string\"; }");
+    let made = vm.call_function("make", &[]).unwrap();
+
+    // Allocate well past the first collection threshold.
+    run(
+        &mut vm,
+        "\
+class Garbage {}
+for (var i = 0; i < 50000; i = i + 1) {
+ Garbage();
+}
+",
+    );
+
+    assert_eq!(String::try_from(&string), Ok("kept by Rust only".to_string()));
+    assert_eq!(String::try_from(made), Ok("freshstring".to_string()));
+}
+
+#[test]
+fn handles_outliving_their_vm_fail_to_convert() {
+    let mut vm = vm();
+    let string = vm.new_string("gone");
+    drop(vm);
+    assert!(String::try_from(&string).is_err());
+}
+
+fn eval(vm: &mut VM, args: &[Handle]) -> Result<Handle, String> {
+    let source = String::try_from(&args[0])?;
+    match vm.interpret(&source) {
+        InterpretResult::Ok => Ok(Handle::from(true)),
+        _ => Ok(Handle::from(false)),
+    }
+}
+
+#[test]
+fn errors_inside_a_nested_interpret_only_unwind_that_script() {
+    let mut vm = vm();
+    vm.define_native("eval", 1, eval);
+    run(
+        &mut vm,
+        "\
+fun outer() {
+ var before = 10;
+ var failed = eval(\"var inner = 1; nil();\");
+ var worked = eval(\"var second = 2;\");
+ if (failed or !worked) return -1;
+ return before + inner + second;
+}
+var result = outer();
+",
+    );
+    assert_eq!(number(&vm, "result"), 13.0);
+}
@@ -0,0 +1,77 @@
+mod common;
+
+use common::vm;
+use rlox::{Diagnostic, StackFrame};
+
+fn runtime_error(source: &str) -> Diagnostic {
+    common::runtime_error(&mut vm(), source)
+}
+
+fn frame(function: Option<&str>, line: usize) -> StackFrame {
+    StackFrame {
+        function: function.map(str::to_string),
+        line,
+    }
+}
+
+#[test]
+fn runtime_errors_report_the_line_of_each_frame() {
+    let source = "\
+fun inner(a) {
+ var b = a;
+ return b
+ +
+ nil;
+}
+
+fun outer() {
+ var x = 1;
+ return inner(x);
+}
+
+var y = 2;
+outer();
+";
+    let diagnostic = runtime_error(source);
+    assert_eq!(diagnostic.line, 4);
+    assert_eq!(
+        diagnostic.stack_trace,
+        Some(vec![
+            frame(Some("inner"), 4),
+            frame(Some("outer"), 10),
+            frame(None, 14),
+        ])
+    );
+}
+
+#[test]
+fn runtime_errors_after_a_loop_report_the_right_line() {
+    let source = "\
+var i = 0;
+while (i < 3) {
+ i = i + 1;
+}
+print i;
+i();
+";
+    let diagnostic = runtime_error(source);
+    assert_eq!(diagnostic.stack_trace, Some(vec![frame(None, 6)]));
+}
+
+#[test]
+fn runtime_errors_point_at_the_target_or_call() {
+    // (source, text the error should underline, where it starts)
+    let cases = [
+        ("missing = 1 +\n 2;", "missing", 0),
+        ("var a = 1;\na.field = 2;", "field", 13),
+        ("var a = 1;\na.field += 2;", "field", 13),
+        ("var a = 1;\na.field++;", "field", 13),
+        ("var f = 1;\nf(\n 1);", "(", 12),
+        ("var a = 1;\na.method(\n);", "method", 13),
+    ];
+
+    for &(source, text, start) in cases.iter() {
+        let diagnostic = runtime_error(source);
+        assert_eq!(diagnostic.span, Some(start..start + text.len()), "in {:?}", source);
+    }
+}